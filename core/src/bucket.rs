@@ -0,0 +1,96 @@
+use crate::config::Algorithm;
+use crate::gcra::{Gcra, LeakyBucket, TokenBucket};
+use crate::sliding_window::SlidingWindow;
+use serde::{Deserialize, Serialize};
+
+/// Per-key limiter state for whichever algorithm the policy selected.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum BucketState {
+    TokenBucket(TokenBucket),
+    Gcra(Gcra),
+    SlidingWindow(SlidingWindow),
+    LeakyBucket(LeakyBucket),
+}
+
+impl BucketState {
+    pub fn new(algorithm: Algorithm, burst: u32, window_seconds: u32, now_ms: u64) -> Self {
+        match algorithm {
+            Algorithm::TokenBucket => BucketState::TokenBucket(TokenBucket::new(burst, now_ms)),
+            Algorithm::Gcra => BucketState::Gcra(Gcra::new(now_ms)),
+            Algorithm::SlidingWindow => {
+                BucketState::SlidingWindow(SlidingWindow::new(window_ms(window_seconds), now_ms))
+            }
+            Algorithm::LeakyBucket => BucketState::LeakyBucket(LeakyBucket::new(now_ms)),
+        }
+    }
+
+    /// The sliding window admits `limit_per_second * window_seconds` requests
+    /// per window; the rate-based algorithms refill at `limit_per_second` up to
+    /// `burst`. The leaky bucket admits with a shaping delay, returned in place
+    /// of the retry hint. `reserve` is the fraction of that capacity (`burst`,
+    /// or the window's limit) the request must leave untouched.
+    pub fn consume(
+        &mut self,
+        limit_per_second: u32,
+        burst: u32,
+        window_seconds: u32,
+        cost: f64,
+        reserve: f64,
+        now_ms: u64,
+    ) -> (bool, Option<u32>) {
+        let burst_reserve = f64::from(burst) * reserve;
+        match self {
+            BucketState::TokenBucket(bucket) => {
+                bucket.consume(limit_per_second, burst, cost, burst_reserve, now_ms)
+            }
+            BucketState::Gcra(gcra) => {
+                gcra.consume(limit_per_second, burst, cost, burst_reserve, now_ms)
+            }
+            BucketState::SlidingWindow(window) => {
+                let limit = limit_per_second.saturating_mul(window_seconds);
+                window.consume(
+                    limit,
+                    window_ms(window_seconds),
+                    cost,
+                    f64::from(limit) * reserve,
+                    now_ms,
+                )
+            }
+            BucketState::LeakyBucket(bucket) => {
+                bucket.consume(limit_per_second, burst, cost, burst_reserve, now_ms)
+            }
+        }
+    }
+
+    /// Credits `cost` back, never beyond a full `burst` (or an empty window).
+    pub fn refund(
+        &mut self,
+        limit_per_second: u32,
+        burst: u32,
+        window_seconds: u32,
+        cost: f64,
+        now_ms: u64,
+    ) {
+        match self {
+            BucketState::TokenBucket(bucket) => bucket.refund(burst, cost),
+            BucketState::Gcra(gcra) => gcra.refund(limit_per_second, cost, now_ms),
+            BucketState::SlidingWindow(window) => {
+                window.refund(window_ms(window_seconds), cost, now_ms)
+            }
+            BucketState::LeakyBucket(bucket) => bucket.refund(limit_per_second, cost, now_ms),
+        }
+    }
+
+    pub fn idle_for(&self, now_ms: u64) -> u64 {
+        match self {
+            BucketState::TokenBucket(bucket) => bucket.idle_for(now_ms),
+            BucketState::Gcra(gcra) => gcra.idle_for(now_ms),
+            BucketState::SlidingWindow(window) => window.idle_for(now_ms),
+            BucketState::LeakyBucket(bucket) => bucket.idle_for(now_ms),
+        }
+    }
+}
+
+fn window_ms(window_seconds: u32) -> u64 {
+    u64::from(window_seconds) * 1000
+}
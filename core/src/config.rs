@@ -1,7 +1,18 @@
+use crate::enrich::{CompiledEnrichStep, EnrichStep};
 use crate::error::{FluxgateError, Result};
-use crate::policy::PolicyMatcher;
+use crate::policy::{value_to_string, MatchScope, PolicyMatcher};
+use crate::quota::Quota;
 use indexmap::IndexMap;
-use serde::{Deserialize, Serialize};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Decision log records buffered between drains unless `decisionLogMaxLines`
+/// says otherwise.
+pub const DEFAULT_DECISION_LOG_MAX_LINES: u32 = 10_000;
+
+/// Seconds a concurrency slot is held before it expires unreleased, unless a
+/// policy sets `leaseSeconds`.
+pub const DEFAULT_LEASE_SECONDS: u32 = 60;
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
@@ -11,6 +22,26 @@ pub struct FluxgateInit {
     #[serde(default)]
     pub config_text: Option<String>,
     #[serde(default)]
+    pub config_texts: Option<Vec<String>>,
+    #[serde(default)]
+    pub sets: Option<IndexMap<String, Vec<serde_json::Value>>>,
+    #[serde(default)]
+    pub matchers: Option<IndexMap<String, String>>,
+    #[serde(default)]
+    pub enrich: Option<Vec<EnrichStep>>,
+    #[serde(default)]
+    pub environments: Option<IndexMap<String, Environment>>,
+    #[serde(default)]
+    pub environment: Option<String>,
+    #[serde(default)]
+    pub decision_log: Option<bool>,
+    #[serde(default)]
+    pub decision_log_max_lines: Option<u32>,
+    #[serde(default)]
+    pub evaluation_budget: Option<EvaluationBudget>,
+    #[serde(default)]
+    pub max_checks_per_second: Option<u32>,
+    #[serde(default)]
     pub key_secret: Option<String>,
     #[serde(default)]
     pub slices: Option<u32>,
@@ -36,7 +67,78 @@ pub struct FluxgatePolicy {
     pub burst: u32,
     pub window_seconds: u32,
     #[serde(default)]
+    pub parent: Option<String>,
+    #[serde(default)]
     pub action: Option<PolicyAction>,
+    #[serde(default)]
+    pub algorithm: Option<Algorithm>,
+    #[serde(default)]
+    pub quota: Option<Quota>,
+    #[serde(default)]
+    pub tiers: Vec<PolicyTier>,
+    #[serde(default)]
+    pub priority_reserve: Option<PriorityReserve>,
+    #[serde(default)]
+    pub max_concurrent: Option<u32>,
+    #[serde(default)]
+    pub lease_seconds: Option<u32>,
+    #[serde(default)]
+    pub bucket_ttl_seconds: Option<u32>,
+    #[serde(default)]
+    pub transition_seconds: Option<u32>,
+    #[serde(default)]
+    pub overrides: Vec<LimitOverride>,
+    #[serde(default)]
+    pub penalty: Option<Penalty>,
+    #[serde(default)]
+    pub deny_body: Option<String>,
+    #[serde(default)]
+    pub deny_content_type: Option<String>,
+}
+
+/// An extra `limit` per rolling `window_seconds` stacked on a policy's base
+/// limiter, sharing its key.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyTier {
+    pub limit: u32,
+    pub window_seconds: u32,
+}
+
+/// Replacement limits for the single key that `key` resolves to, e.g.
+/// `{ key: { "header:x-api-key": "acme" }, limitPerSecond: 500 }`. Fields
+/// left unset keep the policy's value.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LimitOverride {
+    /// Key fields with their JSON values already converted to strings, so
+    /// overrides stay encodable in snapshots.
+    #[serde(deserialize_with = "deserialize_override_key")]
+    pub key: IndexMap<String, String>,
+    #[serde(default)]
+    pub limit_per_second: Option<u32>,
+    #[serde(default)]
+    pub burst: Option<u32>,
+}
+
+/// Bans a key for `ban_seconds` once the policy has denied it
+/// `threshold_denials` times within `window_seconds` (by default the policy's
+/// own `windowSeconds`).
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Penalty {
+    pub threshold_denials: u32,
+    pub ban_seconds: u32,
+    #[serde(default)]
+    pub window_seconds: Option<u32>,
+}
+
+/// A named deployment whose policies run at `scale` times the limits written
+/// in the config, e.g. `staging: { scale: 0.1 }`.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Environment {
+    pub scale: f64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -50,6 +152,41 @@ pub struct CheckRequest {
     pub headers: Option<IndexMap<String, Option<String>>>,
     #[serde(default)]
     pub attrs: Option<IndexMap<String, serde_json::Value>>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_cost")]
+    pub cost: Option<f64>,
+    #[serde(default)]
+    pub priority: Option<Priority>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+/// Fractions of a policy's capacity that `normal` and `low` priority requests
+/// must leave free, so the remainder is only ever used by higher priorities.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PriorityReserve {
+    #[serde(default)]
+    pub normal: f64,
+    #[serde(default)]
+    pub low: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckOptions {
+    #[serde(default)]
+    pub dry_run: bool,
+    #[serde(default)]
+    pub skip_policies: Vec<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -58,6 +195,25 @@ pub struct CheckDecision {
     pub allowed: bool,
     #[serde(default)]
     pub retry_after_ms: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota_remaining: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota_reset_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub concurrency_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay_ms: Option<u32>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub banned: bool,
+}
+
+/// A key serving a penalty ban, as reported by `banned_keys`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BannedKey {
+    pub policy_id: String,
+    pub key: String,
+    pub banned_until_ms: u64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -68,6 +224,48 @@ pub struct CheckResult {
     pub retry_after_ms: Option<u32>,
     #[serde(default)]
     pub decisions: IndexMap<String, CheckDecision>,
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay_ms: Option<u32>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub budget_exceeded: bool,
+    /// Set when the instance-wide `maxChecksPerSecond` guard shed the check
+    /// before any policy was evaluated.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub overloaded: bool,
+    /// Enforcing policies that denied the request, in policy order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub denied_by: Vec<String>,
+    /// `annotate` policies that would have denied the request.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub annotated_by: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_denied: Option<String>,
+}
+
+/// Caps how much matching a single check may do. Configs whose policies or
+/// clauses exceed the caps are rejected; at check time each clause evaluated
+/// and each nested attr path segment walked costs one unit of `max_clauses`.
+/// Once a cap would be exceeded the remaining policies are not evaluated and
+/// the request is decided by `on_exceeded` alone.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EvaluationBudget {
+    #[serde(default)]
+    pub max_policies: Option<u32>,
+    #[serde(default)]
+    pub max_clauses: Option<u32>,
+    #[serde(default)]
+    pub on_exceeded: BudgetFallback,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetFallback {
+    #[default]
+    Open,
+    Closed,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -75,6 +273,17 @@ pub struct CheckResult {
 pub struct FluxgateConfig {
     pub policies: Vec<CompiledPolicy>,
     #[serde(default)]
+    pub enrich: Vec<CompiledEnrichStep>,
+    #[serde(default)]
+    pub decision_log: bool,
+    /// Records kept before the oldest are dropped.
+    #[serde(default)]
+    pub decision_log_max_lines: u32,
+    #[serde(default)]
+    pub evaluation_budget: Option<EvaluationBudget>,
+    #[serde(default)]
+    pub max_checks_per_second: Option<u32>,
+    #[serde(default)]
     pub key_secret: Option<String>,
     #[serde(default)]
     pub slices: Option<u32>,
@@ -94,6 +303,9 @@ pub struct FluxgateConfig {
 pub struct CompiledPolicy {
     pub definition: FluxgatePolicy,
     pub matcher: PolicyMatcher,
+    /// Index of the `parent` policy, resolved once all policies are known.
+    #[serde(default)]
+    pub parent: Option<usize>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -105,58 +317,209 @@ pub enum PolicyAction {
     Annotate,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    #[default]
+    TokenBucket,
+    Gcra,
+    SlidingWindow,
+    LeakyBucket,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct DocumentPolicies {
+    #[serde(default)]
+    pub sets: IndexMap<String, Vec<serde_json::Value>>,
+    #[serde(default)]
+    pub matchers: IndexMap<String, String>,
+    #[serde(default)]
+    pub enrich: Vec<EnrichStep>,
+    #[serde(default)]
+    pub environments: IndexMap<String, Environment>,
+    #[serde(default)]
     pub policies: Vec<FluxgatePolicy>,
 }
 
 impl FluxgateInit {
     pub fn into_config(self) -> Result<FluxgateConfig> {
-        let mut policies = self.policies.unwrap_or_default();
+        let mut doc = DocumentPolicies {
+            sets: self.sets.unwrap_or_default(),
+            matchers: self.matchers.unwrap_or_default(),
+            enrich: self.enrich.unwrap_or_default(),
+            environments: self.environments.unwrap_or_default(),
+            policies: self.policies.unwrap_or_default(),
+        };
 
-        if let Some(text) = self.config_text {
-            if !text.trim().is_empty() {
-                #[cfg(feature = "yaml")]
-                {
-                    let doc: DocumentPolicies = serde_yaml::from_str(&text).map_err(|err| {
-                        FluxgateError::InvalidConfig(format!("yaml parse error: {err}"))
-                    })?;
-                    policies.extend(doc.policies);
-                }
-
-                #[cfg(not(feature = "yaml"))]
-                {
-                    return Err(FluxgateError::InvalidConfig(
-                        "configText provided but YAML support is disabled".to_string(),
-                    ));
-                }
-            }
+        let texts = self
+            .config_text
+            .into_iter()
+            .chain(self.config_texts.unwrap_or_default());
+        for text in texts.filter(|text| !text.trim().is_empty()) {
+            doc.overlay(parse_document(&text)?);
         }
 
+        let DocumentPolicies {
+            sets,
+            matchers,
+            enrich,
+            environments,
+            mut policies,
+        } = doc;
+
         if policies.is_empty() {
             return Err(FluxgateError::InvalidConfig(
                 "at least one policy must be provided".to_string(),
             ));
         }
 
+        if self.max_checks_per_second == Some(0) {
+            return Err(FluxgateError::InvalidConfig(
+                "maxChecksPerSecond must be greater than zero".to_string(),
+            ));
+        }
+
+        if self.decision_log_max_lines == Some(0) {
+            return Err(FluxgateError::InvalidConfig(
+                "decisionLogMaxLines must be greater than zero".to_string(),
+            ));
+        }
+
+        if let Some(name) = &self.environment {
+            let environment = environments.get(name).ok_or_else(|| {
+                FluxgateError::InvalidConfig(format!("unknown environment {name}"))
+            })?;
+            if !environment.scale.is_finite() || environment.scale <= 0.0 {
+                return Err(FluxgateError::InvalidConfig(format!(
+                    "environment {name} scale must be greater than zero"
+                )));
+            }
+            for policy in &mut policies {
+                policy.scale_limits(environment.scale);
+            }
+        }
+
+        let scope = compile_scope(sets, matchers)?;
+        let enrich = enrich
+            .into_iter()
+            .enumerate()
+            .map(|(idx, step)| {
+                CompiledEnrichStep::compile(step).map_err(|err| {
+                    FluxgateError::InvalidConfig(format!("enrich step {idx}: {err}"))
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
         let compiled = policies
             .into_iter()
             .map(|policy| {
-                let matcher = PolicyMatcher::from_rule(&policy.match_rule).map_err(|err| {
-                    FluxgateError::InvalidConfig(format!(
-                        "policy {} match parse error: {err}",
+                let matcher =
+                    PolicyMatcher::from_rule(&policy.match_rule, &scope).map_err(|err| {
+                        FluxgateError::InvalidConfig(format!(
+                            "policy {} match parse error: {err}",
+                            policy.id
+                        ))
+                    })?;
+                if policy.algorithm == Some(Algorithm::SlidingWindow) && policy.window_seconds == 0
+                {
+                    return Err(FluxgateError::InvalidConfig(format!(
+                        "policy {} sliding_window requires windowSeconds > 0",
+                        policy.id
+                    )));
+                }
+                if policy.tiers.iter().any(|tier| tier.window_seconds == 0) {
+                    return Err(FluxgateError::InvalidConfig(format!(
+                        "policy {} tiers require windowSeconds > 0",
+                        policy.id
+                    )));
+                }
+                let reserve_in_range = |fraction: f64| (0.0..1.0).contains(&fraction);
+                if let Some(reserve) = policy.priority_reserve {
+                    if !reserve_in_range(reserve.normal) || !reserve_in_range(reserve.low) {
+                        return Err(FluxgateError::InvalidConfig(format!(
+                            "policy {} priorityReserve fractions must be in [0, 1)",
+                            policy.id
+                        )));
+                    }
+                }
+                if policy.max_concurrent == Some(0) {
+                    return Err(FluxgateError::InvalidConfig(format!(
+                        "policy {} maxConcurrent must be greater than zero",
+                        policy.id
+                    )));
+                }
+                if policy.lease_seconds == Some(0) {
+                    return Err(FluxgateError::InvalidConfig(format!(
+                        "policy {} leaseSeconds must be greater than zero",
                         policy.id
-                    ))
-                })?;
-                Ok(CompiledPolicy {
+                    )));
+                }
+                if policy.bucket_ttl_seconds == Some(0) {
+                    return Err(FluxgateError::InvalidConfig(format!(
+                        "policy {} bucketTtlSeconds must be greater than zero",
+                        policy.id
+                    )));
+                }
+                if let Some(penalty) = policy.penalty {
+                    if penalty.threshold_denials == 0 || penalty.ban_seconds == 0 {
+                        return Err(FluxgateError::InvalidConfig(format!(
+                            "policy {} penalty thresholdDenials and banSeconds must be greater than zero",
+                            policy.id
+                        )));
+                    }
+                    if penalty.window_ms(policy.window_seconds) == 0 {
+                        return Err(FluxgateError::InvalidConfig(format!(
+                            "policy {} penalty requires windowSeconds > 0",
+                            policy.id
+                        )));
+                    }
+                }
+                if policy.transition_seconds == Some(0) {
+                    return Err(FluxgateError::InvalidConfig(format!(
+                        "policy {} transitionSeconds must be greater than zero",
+                        policy.id
+                    )));
+                }
+                let compiled = CompiledPolicy {
                     definition: policy,
                     matcher,
-                })
+                    parent: None,
+                };
+                compiled.override_captures()?;
+                Ok(compiled)
             })
             .collect::<Result<Vec<_>>>()?;
+        let compiled = resolve_parents(compiled)?;
+        if let Some(budget) = self.evaluation_budget {
+            let clauses: usize = compiled
+                .iter()
+                .map(|policy| policy.matcher.clause_count())
+                .sum();
+            if budget
+                .max_policies
+                .is_some_and(|max| compiled.len() > max as usize)
+            {
+                return Err(FluxgateError::InvalidConfig(format!(
+                    "evaluationBudget maxPolicies is below the {} configured policies",
+                    compiled.len()
+                )));
+            }
+            if budget.max_clauses.is_some_and(|max| clauses > max as usize) {
+                return Err(FluxgateError::InvalidConfig(format!(
+                    "evaluationBudget maxClauses is below the {clauses} configured clauses"
+                )));
+            }
+        }
 
         Ok(FluxgateConfig {
             policies: compiled,
+            enrich,
+            decision_log: self.decision_log.unwrap_or(false),
+            decision_log_max_lines: self
+                .decision_log_max_lines
+                .unwrap_or(DEFAULT_DECISION_LOG_MAX_LINES),
+            evaluation_budget: self.evaluation_budget,
+            max_checks_per_second: self.max_checks_per_second,
             key_secret: self.key_secret,
             slices: self.slices,
             sketch_width: self.sketch_width,
@@ -168,12 +531,430 @@ impl FluxgateInit {
     }
 }
 
+impl DocumentPolicies {
+    /// Layers a later document over this one: policies, sets and matchers with
+    /// the same id/name are replaced in place, new ones are appended, and
+    /// enrich steps run after the existing ones.
+    fn overlay(&mut self, layer: DocumentPolicies) {
+        self.sets.extend(layer.sets);
+        self.matchers.extend(layer.matchers);
+        self.enrich.extend(layer.enrich);
+        self.environments.extend(layer.environments);
+        for policy in layer.policies {
+            match self
+                .policies
+                .iter_mut()
+                .find(|existing| existing.id == policy.id)
+            {
+                Some(existing) => *existing = policy,
+                None => self.policies.push(policy),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "yaml")]
+fn parse_document(text: &str) -> Result<DocumentPolicies> {
+    serde_yaml::from_str(text)
+        .map_err(|err| FluxgateError::InvalidConfig(format!("yaml parse error: {err}")))
+}
+
+#[cfg(not(feature = "yaml"))]
+fn parse_document(_text: &str) -> Result<DocumentPolicies> {
+    Err(FluxgateError::InvalidConfig(
+        "configText provided but YAML support is disabled".to_string(),
+    ))
+}
+
+/// Compiles named matcher fragments in declaration order; a fragment may
+/// reference any set and any fragment declared before it.
+fn compile_scope(
+    sets: IndexMap<String, Vec<serde_json::Value>>,
+    matchers: IndexMap<String, String>,
+) -> Result<MatchScope> {
+    let mut scope = MatchScope::with_sets(sets);
+    for (name, rule) in matchers {
+        let matcher = PolicyMatcher::from_rule(&rule, &scope).map_err(|err| {
+            FluxgateError::InvalidConfig(format!("matcher {name} parse error: {err}"))
+        })?;
+        scope.aliases.insert(name, matcher);
+    }
+    Ok(scope)
+}
+
+/// Links each policy to its `parent` and rejects unknown parents and cycles.
+fn resolve_parents(mut compiled: Vec<CompiledPolicy>) -> Result<Vec<CompiledPolicy>> {
+    for idx in 0..compiled.len() {
+        let Some(parent_id) = compiled[idx].definition.parent.as_deref() else {
+            continue;
+        };
+        let parent = compiled
+            .iter()
+            .position(|policy| policy.definition.id == parent_id)
+            .ok_or_else(|| {
+                FluxgateError::InvalidConfig(format!(
+                    "policy {} has unknown parent {parent_id}",
+                    compiled[idx].definition.id
+                ))
+            })?;
+        compiled[idx].parent = Some(parent);
+    }
+
+    for start in 0..compiled.len() {
+        let mut steps = 0;
+        let mut current = compiled[start].parent;
+        while let Some(idx) = current {
+            steps += 1;
+            if idx == start || steps > compiled.len() {
+                return Err(FluxgateError::InvalidConfig(format!(
+                    "policy {} is its own ancestor",
+                    compiled[start].definition.id
+                )));
+            }
+            current = compiled[idx].parent;
+        }
+    }
+    Ok(compiled)
+}
+
+impl CompiledPolicy {
+    /// Resolves each override's `key` to the captures the policy's matcher
+    /// produces for it, in `overrides` order.
+    pub fn override_captures(&self) -> Result<Vec<IndexMap<String, String>>> {
+        self.definition
+            .overrides
+            .iter()
+            .map(|limit_override| {
+                self.matcher
+                    .captures_for(&limit_override.key)
+                    .map_err(|err| {
+                        FluxgateError::InvalidConfig(format!(
+                            "policy {} override: {err}",
+                            self.definition.id
+                        ))
+                    })
+            })
+            .collect()
+    }
+}
+
+impl FluxgatePolicy {
+    /// Multiplies the rate, burst, tier, quota and override limits by `factor`,
+    /// rounding to the nearest whole unit. A non-zero limit never scales below
+    /// one, so a small environment throttles a policy instead of closing it,
+    /// and scaled-up limits saturate at the type's maximum. Concurrency limits
+    /// are left as written.
+    fn scale_limits(&mut self, factor: f64) {
+        let scale = |limit: u32| scale_limit_u32(limit, factor);
+        self.limit_per_second = scale(self.limit_per_second);
+        self.burst = scale(self.burst);
+        for tier in &mut self.tiers {
+            tier.limit = scale(tier.limit);
+        }
+        if let Some(quota) = &mut self.quota {
+            quota.limit = scale_limit(quota.limit, factor);
+        }
+        for limit_override in &mut self.overrides {
+            limit_override.limit_per_second = limit_override.limit_per_second.map(scale);
+            limit_override.burst = limit_override.burst.map(scale);
+        }
+    }
+}
+
+/// Scales `limit` by `factor`, rounding to the nearest whole unit. A non-zero
+/// limit stays at least one for any positive factor, so only a factor of 0
+/// closes it.
+pub fn scale_limit(limit: u64, factor: f64) -> u64 {
+    if limit == 0 || factor <= 0.0 {
+        return 0;
+    }
+    // Float-to-int casts saturate, so huge products clamp to `u64::MAX`.
+    ((limit as f64 * factor).round() as u64).max(1)
+}
+
+/// `scale_limit` for 32-bit limits, saturating at `u32::MAX`.
+pub fn scale_limit_u32(limit: u32, factor: f64) -> u32 {
+    u32::try_from(scale_limit(u64::from(limit), factor)).unwrap_or(u32::MAX)
+}
+
+/// Rejects negative and non-finite costs when a request is parsed, so they
+/// surface as request parse errors instead of being silently replaced.
+fn deserialize_cost<'de, D>(deserializer: D) -> std::result::Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<f64>::deserialize(deserializer)? {
+        Some(cost) if !cost.is_finite() || cost < 0.0 => Err(D::Error::custom(format!(
+            "cost must be a finite number >= 0, got {cost}"
+        ))),
+        cost => Ok(cost),
+    }
+}
+
+/// Converts each override key field to the string a request would carry for
+/// it (`42` and `"42"` both become `42`). Snapshots already hold the strings.
+fn deserialize_override_key<'de, D>(
+    deserializer: D,
+) -> std::result::Result<IndexMap<String, String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    if !deserializer.is_human_readable() {
+        return IndexMap::deserialize(deserializer);
+    }
+    let fields = IndexMap::<String, serde_json::Value>::deserialize(deserializer)?;
+    Ok(fields
+        .into_iter()
+        .map(|(field, value)| (field, value_to_string(&value)))
+        .collect())
+}
+
+impl CheckRequest {
+    /// Units this request consumes; a missing cost counts as one unit. Parsed
+    /// requests never carry an invalid cost, and one set directly from Rust
+    /// counts as a single unit rather than poisoning the limiter state.
+    pub fn cost(&self) -> f64 {
+        self.cost
+            .filter(|cost| cost.is_finite() && *cost >= 0.0)
+            .unwrap_or(1.0)
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority.unwrap_or_default()
+    }
+}
+
+impl PriorityReserve {
+    pub fn for_priority(&self, priority: Priority) -> f64 {
+        match priority {
+            Priority::High => 0.0,
+            Priority::Normal => self.normal,
+            Priority::Low => self.low,
+        }
+    }
+}
+
+impl Penalty {
+    /// Length of the window denials are counted in, given the policy's own
+    /// `windowSeconds`.
+    pub fn window_ms(&self, policy_window_seconds: u32) -> u64 {
+        u64::from(self.window_seconds.unwrap_or(policy_window_seconds)) * 1000
+    }
+
+    pub fn ban_ms(&self) -> u64 {
+        u64::from(self.ban_seconds) * 1000
+    }
+}
+
+impl PolicyTier {
+    pub fn window_ms(&self) -> u64 {
+        u64::from(self.window_seconds) * 1000
+    }
+}
+
+impl CheckOptions {
+    pub fn skips(&self, policy_id: &str) -> bool {
+        self.skip_policies.iter().any(|id| id == policy_id)
+    }
+}
+
 impl CheckResult {
     pub fn denied(retry_after_ms: Option<u32>, decisions: IndexMap<String, CheckDecision>) -> Self {
         Self {
             allowed: false,
             retry_after_ms,
             decisions,
+            ..Self::default()
         }
     }
 }
+
+#[cfg(all(test, feature = "yaml"))]
+mod tests {
+    use super::{CheckRequest, FluxgateInit};
+
+    #[test]
+    fn later_documents_override_earlier_ones() {
+        let base = "
+policies:
+  - { id: ip-global, match: 'ip:*', limitPerSecond: 100, burst: 100, windowSeconds: 60 }
+  - { id: login, match: 'route:/login', limitPerSecond: 5, burst: 5, windowSeconds: 60 }
+";
+        let overlay = "
+policies:
+  - { id: ip-global, match: 'ip:*', limitPerSecond: 10, burst: 10, windowSeconds: 60 }
+  - { id: search, match: 'route:/search', limitPerSecond: 20, burst: 20, windowSeconds: 60 }
+";
+        let init = FluxgateInit {
+            config_texts: Some(vec![base.to_string(), overlay.to_string()]),
+            ..FluxgateInit::default()
+        };
+
+        let config = init.into_config().unwrap();
+        let ids: Vec<&str> = config
+            .policies
+            .iter()
+            .map(|policy| policy.definition.id.as_str())
+            .collect();
+        assert_eq!(ids, ["ip-global", "login", "search"]);
+        assert_eq!(config.policies[0].definition.limit_per_second, 10);
+    }
+
+    #[test]
+    fn selected_environment_scales_policy_limits() {
+        let text = "
+environments:
+  production: { scale: 1.0 }
+  staging: { scale: 0.1 }
+policies:
+  - id: api
+    match: 'ip:*'
+    limitPerSecond: 100
+    burst: 50
+    windowSeconds: 60
+    tiers: [{ limit: 1000, windowSeconds: 3600 }]
+";
+        let init = |environment: &str| FluxgateInit {
+            config_text: Some(text.to_string()),
+            environment: Some(environment.to_string()),
+            ..FluxgateInit::default()
+        };
+
+        let staging = init("staging").into_config().unwrap();
+        let api = &staging.policies[0].definition;
+        assert_eq!((api.limit_per_second, api.burst), (10, 5));
+        assert_eq!(api.tiers[0].limit, 100);
+
+        let production = init("production").into_config().unwrap();
+        assert_eq!(production.policies[0].definition.limit_per_second, 100);
+        assert!(init("qa").into_config().is_err());
+    }
+
+    #[test]
+    fn matcher_aliases_expand_including_nested_ones() {
+        let text = "
+matchers:
+  authenticated: header:authorization=?
+  v1-authenticated: $authenticated route:/v1/*
+policies:
+  - { id: v1, match: '$v1-authenticated ip:*', limitPerSecond: 5, burst: 5, windowSeconds: 60 }
+";
+        let init = FluxgateInit {
+            config_text: Some(text.to_string()),
+            ..FluxgateInit::default()
+        };
+        let config = init.into_config().unwrap();
+        let matcher = &config.policies[0].matcher;
+        assert_eq!(matcher.clause_count(), 3);
+
+        let request = |authorization: Option<&str>| CheckRequest {
+            ip: Some("10.0.0.1".to_string()),
+            route: Some("/v1/users".to_string()),
+            headers: Some(
+                [(
+                    "authorization".to_string(),
+                    authorization.map(str::to_string),
+                )]
+                .into(),
+            ),
+            ..CheckRequest::default()
+        };
+        assert!(matcher.matches(&request(Some("Bearer t"))).is_some());
+        assert!(matcher.matches(&request(None)).is_none());
+    }
+
+    #[test]
+    fn unknown_or_forward_aliases_are_rejected() {
+        let compile = |text: &str| {
+            FluxgateInit {
+                config_text: Some(text.to_string()),
+                ..FluxgateInit::default()
+            }
+            .into_config()
+        };
+
+        let unknown = compile(
+            "
+policies:
+  - { id: p, match: '$missing ip:*', limitPerSecond: 5, burst: 5, windowSeconds: 60 }
+",
+        )
+        .unwrap_err();
+        assert!(unknown
+            .to_string()
+            .contains("unknown matcher alias: $missing"));
+
+        let forward = compile(
+            "
+matchers:
+  outer: $inner route:/v1/*
+  inner: ip:*
+policies:
+  - { id: p, match: '$outer', limitPerSecond: 5, burst: 5, windowSeconds: 60 }
+",
+        )
+        .unwrap_err();
+        assert!(forward.to_string().contains("matcher outer parse error"));
+    }
+
+    #[test]
+    fn invalid_costs_fail_to_parse() {
+        for cost in ["-1", "-0.5", "1e400"] {
+            let json = format!(r#"{{ "ip": "10.0.0.1", "cost": {cost} }}"#);
+            assert!(
+                serde_json::from_str::<CheckRequest>(&json).is_err(),
+                "{cost}"
+            );
+        }
+        let request: CheckRequest = serde_json::from_str(r#"{ "cost": 2.5 }"#).unwrap();
+        assert_eq!(request.cost(), 2.5);
+        let request: CheckRequest = serde_json::from_str(r#"{ "cost": null }"#).unwrap();
+        assert_eq!(request.cost(), 1.0);
+    }
+
+    #[test]
+    fn scaled_limits_never_round_down_to_zero() {
+        let text = "
+environments:
+  staging: { scale: 0.1 }
+policies:
+  - id: admin
+    match: 'ip:*'
+    limitPerSecond: 3
+    burst: 3
+    windowSeconds: 60
+    quota: { limit: 2, period: day }
+    maxConcurrent: 4
+";
+        let init = FluxgateInit {
+            config_text: Some(text.to_string()),
+            environment: Some("staging".to_string()),
+            ..FluxgateInit::default()
+        };
+
+        let config = init.into_config().unwrap();
+        let admin = &config.policies[0].definition;
+        assert_eq!((admin.limit_per_second, admin.burst), (1, 1));
+        assert_eq!(admin.quota.unwrap().limit, 1);
+        assert_eq!(admin.max_concurrent, Some(4));
+    }
+
+    #[test]
+    fn scaled_up_limits_saturate() {
+        let text = "
+environments:
+  burst-test: { scale: 10 }
+policies:
+  - { id: bulk, match: 'ip:*', limitPerSecond: 500000000, burst: 20, windowSeconds: 60 }
+";
+        let init = FluxgateInit {
+            config_text: Some(text.to_string()),
+            environment: Some("burst-test".to_string()),
+            ..FluxgateInit::default()
+        };
+
+        let config = init.into_config().unwrap();
+        let bulk = &config.policies[0].definition;
+        assert_eq!((bulk.limit_per_second, bulk.burst), (u32::MAX, 200));
+    }
+}
@@ -0,0 +1,90 @@
+use crate::config::{CheckResult, FluxgatePolicy};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DenialResponse {
+    pub policy_id: String,
+    pub content_type: String,
+    pub body: String,
+}
+
+/// Fills `{retry_after_ms}`, `{retry_after_seconds}`, `{policy_id}` and
+/// `{request_id}` in the policy's `denyBody`. Values are JSON-escaped when the
+/// content type is JSON so ids cannot break the document, and a missing retry
+/// hint renders as `null` there (empty otherwise).
+pub fn render(policy: &FluxgatePolicy, result: &CheckResult) -> Option<DenialResponse> {
+    let template = policy.deny_body.as_ref()?;
+    let content_type = policy
+        .deny_content_type
+        .clone()
+        .unwrap_or_else(|| "text/plain".to_string());
+    let json = content_type.contains("json");
+    let escape = |value: &str| {
+        if json {
+            let quoted = serde_json::Value::String(value.to_string()).to_string();
+            quoted[1..quoted.len() - 1].to_string()
+        } else {
+            value.to_string()
+        }
+    };
+
+    let no_hint = if json { "null" } else { "" };
+    let retry_after_ms = result
+        .retry_after_ms
+        .map_or_else(|| no_hint.to_string(), |ms| ms.to_string());
+    let retry_after_seconds = result
+        .retry_after_ms
+        .map_or_else(|| no_hint.to_string(), |ms| ms.div_ceil(1000).to_string());
+    let body = template
+        .replace("{retry_after_ms}", &retry_after_ms)
+        .replace("{retry_after_seconds}", &retry_after_seconds)
+        .replace("{policy_id}", &escape(&policy.id))
+        .replace(
+            "{request_id}",
+            &escape(result.request_id.as_deref().unwrap_or_default()),
+        );
+
+    Some(DenialResponse {
+        policy_id: policy.id.clone(),
+        content_type,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use crate::config::{CheckResult, FluxgatePolicy};
+
+    #[test]
+    fn json_templates_escape_placeholders() {
+        let policy: FluxgatePolicy = serde_json::from_str(
+            r#"{"id":"ip-global","match":"ip:*","limitPerSecond":1,"burst":1,"windowSeconds":1,
+                "denyBody":"{\"error\":\"rate_limited\",\"retry\":{retry_after_seconds},\"req\":\"{request_id}\"}",
+                "denyContentType":"application/json"}"#,
+        )
+        .unwrap();
+        let result = CheckResult {
+            allowed: false,
+            retry_after_ms: Some(1500),
+            request_id: Some("abc\"123".to_string()),
+            ..CheckResult::default()
+        };
+
+        let denial = render(&policy, &result).unwrap();
+        let body: serde_json::Value = serde_json::from_str(&denial.body).unwrap();
+        assert_eq!(body["retry"], 2);
+        assert_eq!(body["req"], "abc\"123");
+        assert_eq!(denial.policy_id, "ip-global");
+
+        // Concurrency and oversized-cost denials carry no hint.
+        let no_hint = CheckResult {
+            retry_after_ms: None,
+            ..result
+        };
+        let denial = render(&policy, &no_hint).unwrap();
+        let body: serde_json::Value = serde_json::from_str(&denial.body).unwrap();
+        assert!(body["retry"].is_null());
+    }
+}
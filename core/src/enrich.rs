@@ -0,0 +1,295 @@
+use crate::config::CheckRequest;
+use crate::key_builder::KeyBuilder;
+use crate::policy::attr_value;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnrichStep {
+    pub from: String,
+    pub to: String,
+    pub transform: EnrichTransform,
+    #[serde(default)]
+    pub length: Option<u32>,
+    #[serde(default)]
+    pub separator: Option<String>,
+    #[serde(default)]
+    pub index: Option<u32>,
+    #[serde(default)]
+    pub buckets: Option<IndexMap<String, Vec<String>>>,
+    #[serde(default)]
+    pub default: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EnrichTransform {
+    Copy,
+    Lowercase,
+    Prefix,
+    Split,
+    Hash,
+    Bucket,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CompiledEnrichStep {
+    source: EnrichSource,
+    target: String,
+    op: EnrichOp,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum EnrichSource {
+    Ip,
+    Route,
+    Header(String),
+    Attr(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum EnrichOp {
+    Copy,
+    Lowercase,
+    Prefix(usize),
+    Split {
+        separator: String,
+        index: usize,
+    },
+    Hash,
+    Bucket {
+        rules: Vec<(String, Vec<String>)>,
+        default: Option<String>,
+    },
+}
+
+impl CompiledEnrichStep {
+    pub fn compile(step: EnrichStep) -> Result<Self, String> {
+        let source = EnrichSource::parse(&step.from)?;
+        if step.to.trim().is_empty() {
+            return Err("enrich step target attr must not be empty".to_string());
+        }
+        let op = match step.transform {
+            EnrichTransform::Copy => EnrichOp::Copy,
+            EnrichTransform::Lowercase => EnrichOp::Lowercase,
+            EnrichTransform::Prefix => {
+                let length = step
+                    .length
+                    .ok_or_else(|| "prefix transform requires length".to_string())?;
+                EnrichOp::Prefix(length as usize)
+            }
+            EnrichTransform::Split => {
+                let separator = step
+                    .separator
+                    .filter(|separator| !separator.is_empty())
+                    .ok_or_else(|| "split transform requires a separator".to_string())?;
+                EnrichOp::Split {
+                    separator,
+                    index: step.index.unwrap_or(0) as usize,
+                }
+            }
+            EnrichTransform::Hash => EnrichOp::Hash,
+            EnrichTransform::Bucket => {
+                let buckets = step
+                    .buckets
+                    .ok_or_else(|| "bucket transform requires buckets".to_string())?;
+                let rules = buckets
+                    .into_iter()
+                    .map(|(name, needles)| {
+                        let needles = needles.iter().map(|needle| needle.to_lowercase()).collect();
+                        (name, needles)
+                    })
+                    .collect();
+                EnrichOp::Bucket {
+                    rules,
+                    default: step.default,
+                }
+            }
+        };
+        Ok(Self {
+            source,
+            target: step.to,
+            op,
+        })
+    }
+
+    /// Derives the target attr from the source value. Steps whose source is
+    /// missing (or that produce no value) leave the request untouched.
+    pub fn apply(&self, key_builder: &KeyBuilder, request: &mut CheckRequest) {
+        let Some(value) = self.source.read(request) else {
+            return;
+        };
+        let Some(derived) = self.op.apply(key_builder, &value) else {
+            return;
+        };
+        request
+            .attrs
+            .get_or_insert_with(IndexMap::new)
+            .insert(self.target.clone(), serde_json::Value::String(derived));
+    }
+}
+
+impl EnrichSource {
+    fn parse(input: &str) -> Result<Self, String> {
+        let input = input.trim();
+        if input == "ip" {
+            return Ok(EnrichSource::Ip);
+        }
+        if input == "route" {
+            return Ok(EnrichSource::Route);
+        }
+        if let Some(name) = input.strip_prefix("header:") {
+            return Ok(EnrichSource::Header(name.to_string()));
+        }
+        if let Some(name) = input.strip_prefix("attr:") {
+            return Ok(EnrichSource::Attr(name.to_string()));
+        }
+        Err(format!("unsupported enrich source: {input}"))
+    }
+
+    fn read(&self, request: &CheckRequest) -> Option<String> {
+        match self {
+            EnrichSource::Ip => request.ip.clone(),
+            EnrichSource::Route => request.route.clone(),
+            EnrichSource::Header(name) => request
+                .headers
+                .as_ref()
+                .and_then(|headers| headers.get(name))
+                .cloned()
+                .flatten(),
+            EnrichSource::Attr(name) => request
+                .attrs
+                .as_ref()
+                .and_then(|attrs| attr_value(attrs, name))
+                .and_then(|value| match value {
+                    serde_json::Value::Null => None,
+                    serde_json::Value::String(s) => Some(s.clone()),
+                    other => Some(other.to_string()),
+                }),
+        }
+    }
+}
+
+impl EnrichOp {
+    fn apply(&self, key_builder: &KeyBuilder, value: &str) -> Option<String> {
+        match self {
+            EnrichOp::Copy => Some(value.to_string()),
+            EnrichOp::Lowercase => Some(value.to_lowercase()),
+            EnrichOp::Prefix(length) => Some(value.chars().take(*length).collect()),
+            EnrichOp::Split { separator, index } => value
+                .split(separator.as_str())
+                .nth(*index)
+                .map(str::to_string),
+            EnrichOp::Hash => Some(format!("{:016x}", key_builder.hash_value(value))),
+            EnrichOp::Bucket { rules, default } => {
+                let lowered = value.to_lowercase();
+                rules
+                    .iter()
+                    .find(|(_, needles)| needles.iter().any(|needle| lowered.contains(needle)))
+                    .map(|(name, _)| name.clone())
+                    .or_else(|| default.clone())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompiledEnrichStep, EnrichStep};
+    use crate::config::CheckRequest;
+    use crate::key_builder::KeyBuilder;
+    use serde_json::json;
+
+    fn enrich(step: serde_json::Value, request: CheckRequest) -> Option<serde_json::Value> {
+        let step: EnrichStep = serde_json::from_value(step).unwrap();
+        let target = step.to.clone();
+        let mut request = request;
+        CompiledEnrichStep::compile(step)
+            .unwrap()
+            .apply(&KeyBuilder::new(Some("enrich-secret")), &mut request);
+        request.attrs?.get(&target).cloned()
+    }
+
+    fn header(value: &str) -> CheckRequest {
+        CheckRequest {
+            headers: Some([("x-in".to_string(), Some(value.to_string()))].into()),
+            ..CheckRequest::default()
+        }
+    }
+
+    #[test]
+    fn copy_and_lowercase_read_every_source() {
+        let request = CheckRequest {
+            ip: Some("10.0.0.1".to_string()),
+            route: Some("/V1/Users".to_string()),
+            attrs: Some([("plan".to_string(), json!(3))].into()),
+            ..CheckRequest::default()
+        };
+        let copy = |from: &str| json!({ "from": from, "to": "out", "transform": "copy" });
+
+        assert_eq!(enrich(copy("ip"), request.clone()), Some(json!("10.0.0.1")));
+        assert_eq!(enrich(copy("attr:plan"), request.clone()), Some(json!("3")));
+        assert_eq!(
+            enrich(copy("header:x-in"), header("Value")),
+            Some(json!("Value"))
+        );
+        let lowercase = json!({ "from": "route", "to": "out", "transform": "lowercase" });
+        assert_eq!(enrich(lowercase, request), Some(json!("/v1/users")));
+    }
+
+    #[test]
+    fn missing_source_leaves_the_request_untouched() {
+        let step = json!({ "from": "header:x-in", "to": "out", "transform": "copy" });
+        assert_eq!(enrich(step, CheckRequest::default()), None);
+    }
+
+    #[test]
+    fn prefix_and_split_take_part_of_the_value() {
+        let prefix =
+            json!({ "from": "header:x-in", "to": "out", "transform": "prefix", "length": 4 });
+        assert_eq!(enrich(prefix, header("sk_live_123")), Some(json!("sk_l")));
+
+        let split = |index: u32| {
+            json!({ "from": "header:x-in", "to": "out", "transform": "split",
+                    "separator": "@", "index": index })
+        };
+        assert_eq!(
+            enrich(split(1), header("ada@example.com")),
+            Some(json!("example.com"))
+        );
+        assert_eq!(enrich(split(2), header("ada@example.com")), None);
+    }
+
+    #[test]
+    fn hash_is_keyed_and_stable() {
+        let step = json!({ "from": "header:x-in", "to": "out", "transform": "hash" });
+        let expected = format!(
+            "{:016x}",
+            KeyBuilder::new(Some("enrich-secret")).hash_value("ada@example.com")
+        );
+        assert_eq!(
+            enrich(step, header("ada@example.com")),
+            Some(json!(expected))
+        );
+    }
+
+    #[test]
+    fn bucket_picks_the_first_match_or_the_default() {
+        let step = |default: Option<&str>| {
+            json!({ "from": "header:x-in", "to": "out", "transform": "bucket",
+                    "buckets": { "bot": ["bot", "spider"], "cli": ["curl"] },
+                    "default": default })
+        };
+        assert_eq!(
+            enrich(step(None), header("Googlebot/2.1")),
+            Some(json!("bot"))
+        );
+        assert_eq!(enrich(step(None), header("curl/8.0")), Some(json!("cli")));
+        assert_eq!(
+            enrich(step(Some("browser")), header("Mozilla/5.0")),
+            Some(json!("browser"))
+        );
+        assert_eq!(enrich(step(None), header("Mozilla/5.0")), None);
+    }
+}
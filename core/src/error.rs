@@ -4,6 +4,8 @@ use thiserror::Error;
 pub enum FluxgateError {
     #[error("invalid configuration: {0}")]
     InvalidConfig(String),
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
     #[error("serialization error: {0}")]
     Serialization(String),
 }
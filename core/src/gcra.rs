@@ -14,10 +14,13 @@ impl TokenBucket {
         }
     }
 
+    /// Admits the request only if `reserve` tokens are still left afterwards.
     pub fn consume(
         &mut self,
         limit_per_second: u32,
         burst: u32,
+        cost: f64,
+        reserve: f64,
         now_ms: u64,
     ) -> (bool, Option<u32>) {
         if limit_per_second == 0 {
@@ -32,39 +35,221 @@ impl TokenBucket {
         self.tokens = (self.tokens + refill).min(burst as f64);
         self.last_ms = now_ms;
 
-        if self.tokens >= 1.0 {
-            self.tokens -= 1.0;
+        let needed = cost + reserve;
+        if self.tokens >= needed {
+            self.tokens -= cost;
             return (true, None);
         }
 
-        let missing = 1.0 - self.tokens;
+        if needed > burst as f64 {
+            // The bucket can never hold enough tokens for this request.
+            return (false, None);
+        }
+
+        let missing = needed - self.tokens;
         let wait_ms = ((missing / rate) * 1000.0).ceil();
         (false, Some(wait_ms.max(0.0) as u32))
     }
 
+    pub fn idle_for(&self, now_ms: u64) -> u64 {
+        now_ms.saturating_sub(self.last_ms)
+    }
+
+    pub fn refund(&mut self, burst: u32, cost: f64) {
+        self.tokens = (self.tokens + cost).min(burst as f64);
+    }
+
     #[cfg(test)]
     pub fn remaining_tokens(&self) -> f64 {
         self.tokens
     }
 }
 
+/// Generic cell rate algorithm: tracks the theoretical arrival time (TAT) of
+/// the next conforming request in integer nanoseconds instead of a token count.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Gcra {
+    tat_ns: u64,
+    last_ms: u64,
+}
+
+impl Gcra {
+    pub fn new(now_ms: u64) -> Self {
+        Self {
+            tat_ns: now_ms * NANOS_PER_MS,
+            last_ms: now_ms,
+        }
+    }
+
+    /// `reserve` emission intervals of the burst tolerance are held back.
+    pub fn consume(
+        &mut self,
+        limit_per_second: u32,
+        burst: u32,
+        cost: f64,
+        reserve: f64,
+        now_ms: u64,
+    ) -> (bool, Option<u32>) {
+        self.last_ms = now_ms;
+        if limit_per_second == 0 {
+            return (false, None);
+        }
+
+        let now_ns = now_ms * NANOS_PER_MS;
+        let emission_ns = NANOS_PER_SECOND / u64::from(limit_per_second);
+        let reserve_ns = (emission_ns as f64 * reserve).round() as u64;
+        let tolerance_ns = (emission_ns * u64::from(burst)).saturating_sub(reserve_ns);
+        let increment_ns = (emission_ns as f64 * cost).round() as u64;
+        if increment_ns > tolerance_ns {
+            return (false, None);
+        }
+        let next_tat = self.tat_ns.max(now_ns) + increment_ns;
+
+        if next_tat - now_ns <= tolerance_ns {
+            self.tat_ns = next_tat;
+            return (true, None);
+        }
+
+        let wait_ns = next_tat - now_ns - tolerance_ns;
+        let wait_ms = wait_ns.div_ceil(NANOS_PER_MS);
+        (false, Some(wait_ms.min(u64::from(u32::MAX)) as u32))
+    }
+
+    pub fn idle_for(&self, now_ms: u64) -> u64 {
+        now_ms.saturating_sub(self.last_ms)
+    }
+
+    /// Moves the TAT back by `cost` emission intervals, but never before `now`,
+    /// which is as much credit as a full burst.
+    pub fn refund(&mut self, limit_per_second: u32, cost: f64, now_ms: u64) {
+        self.tat_ns = rewind(self.tat_ns, limit_per_second, cost, now_ms);
+    }
+}
+
+/// Leaky bucket as a meter-as-queue: instead of denying a request that arrives
+/// early it schedules it at the virtual finish time of the queue ahead of it
+/// and reports how long the caller should hold it. Requests that would wait
+/// longer than `burst` emission intervals are denied.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LeakyBucket {
+    finish_ns: u64,
+    last_ms: u64,
+}
+
+impl LeakyBucket {
+    pub fn new(now_ms: u64) -> Self {
+        Self {
+            finish_ns: now_ms * NANOS_PER_MS,
+            last_ms: now_ms,
+        }
+    }
+
+    /// Returns whether the request is admitted and, if so, the delay before it
+    /// should be forwarded (`None` when it can go at once); if not, how long
+    /// until it would fit in the queue.
+    /// `reserve` queue slots are held back.
+    pub fn consume(
+        &mut self,
+        limit_per_second: u32,
+        burst: u32,
+        cost: f64,
+        reserve: f64,
+        now_ms: u64,
+    ) -> (bool, Option<u32>) {
+        self.last_ms = now_ms;
+        if limit_per_second == 0 {
+            return (false, None);
+        }
+
+        let now_ns = now_ms * NANOS_PER_MS;
+        let emission_ns = NANOS_PER_SECOND / u64::from(limit_per_second);
+        let reserve_ns = (emission_ns as f64 * reserve).round() as u64;
+        let max_wait_ns = (emission_ns * u64::from(burst)).saturating_sub(reserve_ns);
+        let increment_ns = (emission_ns as f64 * cost).round() as u64;
+        if increment_ns > max_wait_ns {
+            // The queue can never hold this much work for one request.
+            return (false, None);
+        }
+        let start_ns = self.finish_ns.max(now_ns);
+        let wait_ns = start_ns - now_ns;
+
+        if wait_ns > max_wait_ns {
+            let retry_ms = (wait_ns - max_wait_ns).div_ceil(NANOS_PER_MS);
+            return (false, Some(retry_ms.min(u64::from(u32::MAX)) as u32));
+        }
+
+        self.finish_ns = start_ns.saturating_add(increment_ns);
+        let delay_ms = wait_ns.div_ceil(NANOS_PER_MS);
+        (
+            true,
+            (delay_ms > 0).then(|| delay_ms.min(u64::from(u32::MAX)) as u32),
+        )
+    }
+
+    pub fn idle_for(&self, now_ms: u64) -> u64 {
+        now_ms.saturating_sub(self.last_ms)
+    }
+
+    /// Removes `cost` from the queue ahead of the next request.
+    pub fn refund(&mut self, limit_per_second: u32, cost: f64, now_ms: u64) {
+        self.finish_ns = rewind(self.finish_ns, limit_per_second, cost, now_ms);
+    }
+}
+
+/// Moves a virtual time back by `cost` emission intervals, stopping at `now`.
+fn rewind(time_ns: u64, limit_per_second: u32, cost: f64, now_ms: u64) -> u64 {
+    if limit_per_second == 0 {
+        return time_ns;
+    }
+    let emission_ns = NANOS_PER_SECOND / u64::from(limit_per_second);
+    let credit_ns = (emission_ns as f64 * cost).round() as u64;
+    time_ns.saturating_sub(credit_ns).max(now_ms * NANOS_PER_MS)
+}
+
+const NANOS_PER_MS: u64 = 1_000_000;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
 #[cfg(test)]
 mod tests {
-    use super::TokenBucket;
+    use super::{Gcra, LeakyBucket, TokenBucket};
 
     #[test]
     fn zero_rate_always_denies() {
         let mut bucket = TokenBucket::new(5, 0);
 
-        let (allowed, retry_after) = bucket.consume(0, 5, 0);
+        let (allowed, retry_after) = bucket.consume(0, 5, 1.0, 0.0, 0);
         assert!(!allowed);
         assert_eq!(retry_after, None);
         assert_eq!(bucket.remaining_tokens(), 0.0);
 
         // Even after time has passed, the bucket should not refill.
-        let (allowed, retry_after) = bucket.consume(0, 5, 5_000);
+        let (allowed, retry_after) = bucket.consume(0, 5, 1.0, 0.0, 5_000);
         assert!(!allowed);
         assert_eq!(retry_after, None);
         assert_eq!(bucket.remaining_tokens(), 0.0);
     }
+
+    #[test]
+    fn gcra_allows_burst_then_spaces_requests() {
+        let mut gcra = Gcra::new(1_000);
+
+        assert_eq!(gcra.consume(2, 2, 1.0, 0.0, 1_000), (true, None));
+        assert_eq!(gcra.consume(2, 2, 1.0, 0.0, 1_000), (true, None));
+        assert_eq!(gcra.consume(2, 2, 1.0, 0.0, 1_000), (false, Some(500)));
+        assert_eq!(gcra.consume(2, 2, 1.0, 0.0, 1_499), (false, Some(1)));
+        assert_eq!(gcra.consume(2, 2, 1.0, 0.0, 1_500), (true, None));
+        assert_eq!(gcra.consume(2, 2, 1.0, 0.0, 1_500), (false, Some(500)));
+    }
+
+    #[test]
+    fn leaky_bucket_delays_instead_of_denying() {
+        let mut bucket = LeakyBucket::new(0);
+
+        // 10/s drains one request every 100ms; two may queue behind the first.
+        assert_eq!(bucket.consume(10, 2, 1.0, 0.0, 0), (true, None));
+        assert_eq!(bucket.consume(10, 2, 1.0, 0.0, 0), (true, Some(100)));
+        assert_eq!(bucket.consume(10, 2, 1.0, 0.0, 0), (true, Some(200)));
+        assert_eq!(bucket.consume(10, 2, 1.0, 0.0, 0), (false, Some(100)));
+        assert_eq!(bucket.consume(10, 2, 1.0, 0.0, 150), (true, Some(150)));
+    }
 }
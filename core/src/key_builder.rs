@@ -1,3 +1,4 @@
+use crate::sip;
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use siphasher::sip::SipHasher13;
@@ -31,4 +32,71 @@ impl KeyBuilder {
         }
         hasher.finish()
     }
+
+    pub fn hash_value(&self, value: &str) -> u64 {
+        let mut hasher = SipHasher13::new_with_keys(self.k0, self.k1);
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Hashes many keys at once; equivalent to calling `build_key` for each
+    /// `(policy_id, captured)` pair, but vectorized when `simd128` is enabled.
+    pub fn build_keys(&self, inputs: &[(&str, &IndexMap<String, String>)]) -> Vec<u64> {
+        let messages: Vec<Vec<u8>> = inputs
+            .iter()
+            .map(|(policy_id, captured)| key_material(policy_id, captured))
+            .collect();
+        sip::hash_batch(self.k0, self.k1, &messages)
+    }
+}
+
+/// Serializes the key inputs exactly as `Hash for str` feeds them to the hasher:
+/// the string bytes followed by a `0xff` terminator.
+fn key_material(policy_id: &str, captured: &IndexMap<String, String>) -> Vec<u8> {
+    let mut material = Vec::new();
+    push_str(&mut material, policy_id);
+    for (name, value) in captured.iter() {
+        push_str(&mut material, name);
+        push_str(&mut material, value);
+    }
+    material
+}
+
+fn push_str(material: &mut Vec<u8>, value: &str) {
+    material.extend_from_slice(value.as_bytes());
+    material.push(0xff);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyBuilder;
+    use indexmap::IndexMap;
+
+    #[test]
+    fn batch_keys_match_single_keys() {
+        let builder = KeyBuilder::new(Some("batch-secret"));
+        let mut short = IndexMap::new();
+        short.insert("ip".to_string(), "10.0.0.1".to_string());
+        let mut long = IndexMap::new();
+        long.insert("ip".to_string(), "2001:db8::1".to_string());
+        long.insert(
+            "route".to_string(),
+            "/v1/really/quite/long/route".to_string(),
+        );
+        let empty = IndexMap::new();
+
+        let inputs = [
+            ("ip-global", &short),
+            ("per-route", &long),
+            ("a", &empty),
+            ("ip-global", &long),
+            ("odd-one-out", &short),
+        ];
+        let batch = builder.build_keys(&inputs);
+        let single: Vec<u64> = inputs
+            .iter()
+            .map(|(policy_id, captured)| builder.build_key(policy_id, captured))
+            .collect();
+        assert_eq!(batch, single);
+    }
 }
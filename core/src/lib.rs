@@ -1,16 +1,31 @@
+mod bucket;
 mod config;
+mod denial;
+mod enrich;
 mod error;
 mod gcra;
 mod key_builder;
 mod limiter;
 mod metrics;
 mod policy;
+mod quota;
+mod sip;
+mod sliding_window;
+mod snapshot;
 mod time;
 
-pub use config::{CheckRequest, CheckResult, FluxgateInit, FluxgatePolicy};
+pub use config::{
+    Algorithm, BannedKey, BudgetFallback, CheckOptions, CheckRequest, CheckResult, Environment,
+    EvaluationBudget, FluxgateInit, FluxgatePolicy, LimitOverride, Penalty, PolicyTier, Priority,
+    PriorityReserve,
+};
+pub use denial::DenialResponse;
+pub use enrich::{EnrichStep, EnrichTransform};
 pub use error::{FluxgateError, Result};
 pub use limiter::Fluxgate;
+pub use quota::{Quota, QuotaPeriod};
 
+use indexmap::IndexMap;
 use wasm_bindgen::prelude::*;
 
 type JsResult<T> = std::result::Result<T, JsValue>;
@@ -32,10 +47,39 @@ impl WasmFluxgate {
     }
 
     #[wasm_bindgen]
-    pub fn check(&mut self, req_json: String) -> JsResult<String> {
+    pub fn check(&mut self, req_json: String, options_json: Option<String>) -> JsResult<String> {
         let req: CheckRequest = serde_json::from_str(&req_json)
             .map_err(|err| JsValue::from_str(&format!("request parse error: {err}")))?;
-        let decision = self.inner.check(req);
+        let options: CheckOptions = match options_json {
+            Some(json) => serde_json::from_str(&json)
+                .map_err(|err| JsValue::from_str(&format!("options parse error: {err}")))?,
+            None => CheckOptions::default(),
+        };
+        let decision = self.inner.check_with(req, &options);
+        serde_json::to_string(&decision)
+            .map_err(|err| JsValue::from_str(&format!("result serialize error: {err}")))
+    }
+
+    /// Evaluates a request without consuming tokens or counting it in metrics.
+    #[wasm_bindgen]
+    pub fn peek(&mut self, req_json: String) -> JsResult<String> {
+        let req: CheckRequest = serde_json::from_str(&req_json)
+            .map_err(|err| JsValue::from_str(&format!("request parse error: {err}")))?;
+        let decision = self.inner.peek(req);
+        serde_json::to_string(&decision)
+            .map_err(|err| JsValue::from_str(&format!("result serialize error: {err}")))
+    }
+
+    /// Consumes `n` units for the request from every matching policy, or
+    /// nothing if any enforcing policy would deny it.
+    #[wasm_bindgen]
+    pub fn check_n(&mut self, req_json: String, n: u32) -> JsResult<String> {
+        let req: CheckRequest = serde_json::from_str(&req_json)
+            .map_err(|err| JsValue::from_str(&format!("request parse error: {err}")))?;
+        let decision = self
+            .inner
+            .check_n(req, n)
+            .map_err(|err| JsValue::from_str(&err.to_string()))?;
         serde_json::to_string(&decision)
             .map_err(|err| JsValue::from_str(&format!("result serialize error: {err}")))
     }
@@ -49,11 +93,105 @@ impl WasmFluxgate {
             .map_err(|err| JsValue::from_str(&format!("batch serialize error: {err}")))
     }
 
+    /// Pulls request JSON from `next_request` until it returns `null`/`undefined`
+    /// and hands each result JSON to `on_result`, one request at a time.
+    /// Returns the number of requests checked.
+    #[wasm_bindgen]
+    pub fn check_stream(
+        &mut self,
+        next_request: &js_sys::Function,
+        on_result: &js_sys::Function,
+    ) -> JsResult<u32> {
+        let mut processed = 0u32;
+        loop {
+            let next = next_request.call0(&JsValue::NULL)?;
+            if next.is_null() || next.is_undefined() {
+                break;
+            }
+            let req_json = next
+                .as_string()
+                .ok_or_else(|| JsValue::from_str("stream request must be a JSON string"))?;
+            let result = self.check(req_json, None)?;
+            on_result.call1(&JsValue::NULL, &JsValue::from_str(&result))?;
+            processed += 1;
+        }
+        Ok(processed)
+    }
+
+    /// Renders the configured deny body for a denied result JSON, or returns
+    /// `undefined` when the result was allowed or no template applies.
+    #[wasm_bindgen]
+    pub fn render_denial(&self, result_json: String) -> JsResult<Option<String>> {
+        let result: CheckResult = serde_json::from_str(&result_json)
+            .map_err(|err| JsValue::from_str(&format!("result parse error: {err}")))?;
+        self.inner
+            .render_denial(&result)
+            .map(|denial| {
+                serde_json::to_string(&denial)
+                    .map_err(|err| JsValue::from_str(&format!("denial serialize error: {err}")))
+            })
+            .transpose()
+    }
+
+    /// Returns the oldest slot held by the request's key in each concurrency
+    /// policy it matches.
+    #[wasm_bindgen]
+    pub fn release(&mut self, req_json: String) -> JsResult<u32> {
+        let req: CheckRequest = serde_json::from_str(&req_json)
+            .map_err(|err| JsValue::from_str(&format!("request parse error: {err}")))?;
+        Ok(self.inner.release(req))
+    }
+
+    /// Credits a cancelled request's cost back to the policies it matches.
+    #[wasm_bindgen]
+    pub fn refund(&mut self, req_json: String) -> JsResult<u32> {
+        let req: CheckRequest = serde_json::from_str(&req_json)
+            .map_err(|err| JsValue::from_str(&format!("request parse error: {err}")))?;
+        Ok(self.inner.refund(req))
+    }
+
+    #[wasm_bindgen]
+    pub fn release_key(&mut self, key: String) -> bool {
+        self.inner.release_key(&key)
+    }
+
+    #[wasm_bindgen]
+    pub fn drain_decision_log(&mut self) -> String {
+        self.inner.drain_decision_log()
+    }
+
+    /// Lists the keys currently banned by a policy `penalty` as JSON.
+    #[wasm_bindgen]
+    pub fn banned_keys(&self) -> JsResult<String> {
+        serde_json::to_string(&self.inner.banned_keys())
+            .map_err(|err| JsValue::from_str(&format!("banned keys serialize error: {err}")))
+    }
+
     #[wasm_bindgen]
     pub fn rotate(&mut self) {
         self.inner.rotate();
     }
 
+    /// Scales all effective limits by `factor` (0.0–1.0) to shed load without
+    /// a reload.
+    #[wasm_bindgen]
+    pub fn set_pressure(&mut self, factor: f64) -> JsResult<()> {
+        self.inner
+            .set_pressure(factor)
+            .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
+    /// Replaces the per-key `overrides` of the policies named in
+    /// `overrides_json` (`{ "<policy id>": [override, ...] }`).
+    #[wasm_bindgen]
+    pub fn set_overrides(&mut self, overrides_json: String) -> JsResult<()> {
+        let overrides: IndexMap<String, Vec<LimitOverride>> = serde_json::from_str(&overrides_json)
+            .map_err(|err| JsValue::from_str(&format!("overrides parse error: {err}")))?;
+        self.inner
+            .set_overrides(overrides)
+            .map_err(|err| JsValue::from_str(&err.to_string()))
+    }
+
     #[wasm_bindgen]
     pub fn reload(&mut self, init_json: String) -> JsResult<()> {
         let init: FluxgateInit = serde_json::from_str(&init_json)
@@ -77,6 +215,17 @@ impl WasmFluxgate {
             .map_err(|err| JsValue::from_str(&err.to_string()))
     }
 
+    /// Releases all bucket state without freeing the handle itself.
+    #[wasm_bindgen]
+    pub fn dispose(&mut self) {
+        self.inner.dispose();
+    }
+
+    #[wasm_bindgen]
+    pub fn state_bytes(&self) -> usize {
+        self.inner.state_bytes()
+    }
+
     #[wasm_bindgen]
     pub fn metrics(&self) -> JsResult<String> {
         let metrics = self.inner.metrics();
@@ -1,15 +1,21 @@
+use crate::bucket::BucketState;
 use crate::config::{
-    CheckDecision, CheckRequest, CheckResult, CompiledPolicy, FluxgateConfig, FluxgateInit,
-    PolicyAction,
+    scale_limit_u32, BannedKey, BudgetFallback, CheckDecision, CheckOptions, CheckRequest,
+    CheckResult, CompiledPolicy, EvaluationBudget, FluxgateConfig, FluxgateInit, LimitOverride,
+    PolicyAction, Priority, DEFAULT_LEASE_SECONDS,
 };
+use crate::denial::{self, DenialResponse};
 use crate::error::{FluxgateError, Result};
-use crate::gcra::TokenBucket;
 use crate::key_builder::KeyBuilder;
 use crate::metrics::Metrics;
+use crate::policy::{BudgetExhausted, PolicyMatcher};
+use crate::quota::QuotaWindow;
+use crate::sliding_window::SlidingWindow;
+use crate::snapshot;
 use crate::time;
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Fluxgate {
@@ -17,12 +23,80 @@ pub struct Fluxgate {
     key_builder: KeyBuilder,
     policies: Vec<PolicyState>,
     metrics: Metrics,
+    #[serde(skip)]
+    decision_log: VecDeque<String>,
+    #[serde(skip)]
+    guard: CheckGuard,
+    pressure: f64,
+}
+
+/// Fixed one-second window counting checks against `maxChecksPerSecond`.
+#[derive(Clone, Debug, Default)]
+struct CheckGuard {
+    second: u64,
+    count: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DecisionRecord<'a> {
+    ts_ms: u64,
+    request: &'a CheckRequest,
+    result: &'a CheckResult,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct PolicyState {
     compiled: CompiledPolicy,
-    buckets: HashMap<u64, TokenBucket>,
+    #[serde(serialize_with = "snapshot::sorted")]
+    buckets: HashMap<u64, BucketState>,
+    #[serde(serialize_with = "snapshot::sorted")]
+    quotas: HashMap<u64, QuotaWindow>,
+    #[serde(serialize_with = "snapshot::sorted")]
+    tiers: HashMap<u64, Vec<SlidingWindow>>,
+    #[serde(serialize_with = "snapshot::sorted")]
+    in_flight: HashMap<u64, Vec<Grant>>,
+    /// Id handed to the next concurrency slot taken from this policy.
+    next_grant: u64,
+    #[serde(serialize_with = "snapshot::sorted")]
+    overrides: HashMap<u64, KeyLimits>,
+    #[serde(serialize_with = "snapshot::sorted")]
+    strikes: HashMap<u64, Strikes>,
+    /// Epoch milliseconds at which each banned key is let back in.
+    #[serde(serialize_with = "snapshot::sorted")]
+    bans: HashMap<u64, u64>,
+    transition: Option<LimitTransition>,
+}
+
+/// One concurrency slot held by a key until released or its lease runs out.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Grant {
+    id: u64,
+    acquired_ms: u64,
+}
+
+/// Denials counted towards a `penalty` ban in the window opened by the first.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Strikes {
+    count: u32,
+    window_start_ms: u64,
+}
+
+/// Limits a policy override pins for one key; unset fields keep the policy's.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct KeyLimits {
+    limit_per_second: Option<u32>,
+    burst: Option<u32>,
+}
+
+/// Ramp from the limits a policy had before a reload to its new ones.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct LimitTransition {
+    from_limit_per_second: u32,
+    from_burst: u32,
+    start_ms: u64,
+    duration_ms: u64,
 }
 
 impl Fluxgate {
@@ -37,81 +111,684 @@ impl Fluxgate {
             .policies
             .iter()
             .cloned()
-            .map(PolicyState::new)
-            .collect();
+            .map(|compiled| PolicyState::new(compiled, &key_builder))
+            .collect::<Result<_>>()?;
 
         Ok(Self {
             config,
             key_builder,
             policies,
             metrics: Metrics::default(),
+            decision_log: VecDeque::new(),
+            guard: CheckGuard::default(),
+            pressure: 1.0,
         })
     }
 
     pub fn check(&mut self, request: CheckRequest) -> CheckResult {
+        self.check_with(request, &CheckOptions::default())
+    }
+
+    /// Like `check`, but lets the caller skip policies or evaluate without
+    /// consuming tokens (`dry_run`). Dry runs are not counted in metrics and
+    /// do not use up the `maxChecksPerSecond` allowance.
+    pub fn check_with(&mut self, mut request: CheckRequest, options: &CheckOptions) -> CheckResult {
         let now_ms = time::now_ms();
+        if !options.dry_run && !self.admit_check(now_ms) {
+            return self.overloaded(&request, now_ms);
+        }
+        self.enrich(&mut request);
+        match self.keys_for(&request, options) {
+            Some(keys) => self.apply(&request, &keys, now_ms, options.dry_run),
+            None => self.over_budget(&request, now_ms, options.dry_run),
+        }
+    }
+
+    /// Evaluates every policy without consuming anything, e.g. to show how
+    /// many calls a key has left. Same as a dry-run `check_with`.
+    pub fn peek(&mut self, request: CheckRequest) -> CheckResult {
+        let options = CheckOptions {
+            dry_run: true,
+            ..CheckOptions::default()
+        };
+        self.check_with(request, &options)
+    }
+
+    /// Consumes `n` units (in place of the request's own `cost`) from every
+    /// policy the request matches, or nothing at all: the request is applied
+    /// only if a dry run shows every enforcing policy admitting it. A denial
+    /// still counts towards each denying policy's `penalty`. `n` must be at
+    /// least 1.
+    pub fn check_n(&mut self, mut request: CheckRequest, n: u32) -> Result<CheckResult> {
+        if n == 0 {
+            return Err(FluxgateError::InvalidRequest(
+                "check_n requires n >= 1".to_string(),
+            ));
+        }
+        let now_ms = time::now_ms();
+        if !self.admit_check(now_ms) {
+            return Ok(self.overloaded(&request, now_ms));
+        }
+        request.cost = Some(f64::from(n));
+        self.enrich(&mut request);
+        let Some(keys) = self.keys_for(&request, &CheckOptions::default()) else {
+            return Ok(self.over_budget(&request, now_ms, false));
+        };
+        let trial = self.evaluate(&request, &keys, now_ms, true);
+        let result = if trial.allowed {
+            self.evaluate(&request, &keys, now_ms, false)
+        } else {
+            for (policy, key) in self.policies.iter_mut().zip(&keys) {
+                let (Some(key), Some(decision)) = (key, trial.decisions.get(policy.policy_id()))
+                else {
+                    continue;
+                };
+                if !decision.allowed && !decision.banned {
+                    policy.record_strike(*key, now_ms);
+                }
+            }
+            trial
+        };
+        Ok(self.finish(&request, result, now_ms, false))
+    }
+
+    /// Derives each policy's bucket key for `request`, or `None` once the
+    /// evaluation budget runs out.
+    fn keys_for(&self, request: &CheckRequest, options: &CheckOptions) -> Option<Vec<Option<u64>>> {
+        let mut allowance = Allowance::new(self.config.evaluation_budget.as_ref());
+        let mut keys: Vec<Option<u64>> = Vec::with_capacity(self.policies.len());
+        for policy in &self.policies {
+            if options.skips(policy.policy_id()) {
+                keys.push(None);
+                continue;
+            }
+            let captured = allowance.matches(&policy.compiled.matcher, request).ok()?;
+            keys.push(
+                captured.map(|captured| self.key_builder.build_key(policy.policy_id(), &captured)),
+            );
+        }
+        self.scope_parents(&mut keys);
+        Some(keys)
+    }
+
+    pub fn check_batch(&mut self, mut requests: Vec<CheckRequest>) -> Vec<CheckResult> {
+        for request in &mut requests {
+            self.enrich(request);
+        }
+
+        // Matching never depends on bucket state, so every key in the batch can
+        // be derived (and hashed together) before any tokens are consumed.
+        let mut captures = Vec::new();
+        let mut slots = Vec::with_capacity(requests.len());
+        for request in &requests {
+            let mut allowance = Allowance::new(self.config.evaluation_budget.as_ref());
+            let first_capture = captures.len();
+            let mut row: Vec<Option<usize>> = Vec::with_capacity(self.policies.len());
+            for policy in &self.policies {
+                let Ok(matched) = allowance.matches(&policy.compiled.matcher, request) else {
+                    captures.truncate(first_capture);
+                    break;
+                };
+                row.push(matched.map(|captured| {
+                    captures.push((policy.policy_id(), captured));
+                    captures.len() - 1
+                }));
+            }
+            let within_budget = row.len() == self.policies.len();
+            slots.push(within_budget.then_some(row));
+        }
+
+        let inputs: Vec<(&str, &IndexMap<String, String>)> = captures
+            .iter()
+            .map(|(policy_id, captured)| (*policy_id, captured))
+            .collect();
+        let hashed = self.key_builder.build_keys(&inputs);
+        let keys: Vec<Option<Vec<Option<u64>>>> = slots
+            .into_iter()
+            .map(|row| {
+                let mut row: Vec<Option<u64>> = row?
+                    .into_iter()
+                    .map(|slot| slot.map(|idx| hashed[idx]))
+                    .collect();
+                self.scope_parents(&mut row);
+                Some(row)
+            })
+            .collect();
+
+        requests
+            .iter()
+            .zip(&keys)
+            .map(|(request, row)| {
+                let now_ms = time::now_ms();
+                if !self.admit_check(now_ms) {
+                    return self.overloaded(request, now_ms);
+                }
+                match row {
+                    Some(row) => self.apply(request, row, now_ms, false),
+                    None => self.over_budget(request, now_ms, false),
+                }
+            })
+            .collect()
+    }
+
+    /// A policy named as another's `parent` only applies on behalf of its
+    /// children: its key is kept when some child applies and the parent's own
+    /// rule matches, and cleared otherwise. Each level applies only when the
+    /// level below it did.
+    fn scope_parents(&self, keys: &mut [Option<u64>]) {
+        let parents: Vec<Option<usize>> = self
+            .policies
+            .iter()
+            .map(|policy| policy.compiled.parent)
+            .collect();
+        if parents.iter().all(Option::is_none) {
+            return;
+        }
+
+        let mut is_parent = vec![false; parents.len()];
+        for parent in parents.iter().flatten() {
+            is_parent[*parent] = true;
+        }
+        let mut reached = vec![false; parents.len()];
+        for (idx, parent) in parents.iter().enumerate() {
+            if is_parent[idx] || keys[idx].is_none() {
+                continue;
+            }
+            let mut current = *parent;
+            while let Some(parent) = current {
+                if keys[parent].is_none() || reached[parent] {
+                    break;
+                }
+                reached[parent] = true;
+                current = parents[parent];
+            }
+        }
+        for (idx, key) in keys.iter_mut().enumerate() {
+            if is_parent[idx] && !reached[idx] {
+                *key = None;
+            }
+        }
+    }
+
+    fn enrich(&self, request: &mut CheckRequest) {
+        for step in &self.config.enrich {
+            step.apply(&self.key_builder, request);
+        }
+    }
+
+    fn apply(
+        &mut self,
+        request: &CheckRequest,
+        keys: &[Option<u64>],
+        now_ms: u64,
+        dry_run: bool,
+    ) -> CheckResult {
+        let result = self.evaluate(request, keys, now_ms, dry_run);
+        self.finish(request, result, now_ms, dry_run)
+    }
+
+    /// Runs every keyed policy against its bucket and aggregates the verdicts.
+    fn evaluate(
+        &mut self,
+        request: &CheckRequest,
+        keys: &[Option<u64>],
+        now_ms: u64,
+        dry_run: bool,
+    ) -> CheckResult {
         let mut decisions = IndexMap::new();
         let mut allowed = true;
         let mut retry_after: Option<u32> = None;
+        let mut delay: Option<u32> = None;
+        let mut denied_by = Vec::new();
+        let mut annotated_by = Vec::new();
+        let pressure = self.pressure;
 
-        for policy in &mut self.policies {
-            if let Some((decision, enforce)) = policy.check(&self.key_builder, &request, now_ms) {
-                if enforce && !decision.allowed {
-                    allowed = false;
-                    retry_after = match (retry_after, decision.retry_after_ms) {
-                        (Some(existing), Some(new_retry)) => Some(existing.max(new_retry)),
-                        (None, Some(new_retry)) => Some(new_retry),
-                        (existing, None) => existing,
-                    };
-                }
-                decisions.insert(policy.policy_id().to_string(), decision);
+        for (policy, key) in self.policies.iter_mut().zip(keys) {
+            let Some(key) = key else {
+                continue;
+            };
+            let (decision, enforce) = policy.consume(
+                *key,
+                now_ms,
+                request.cost(),
+                request.priority(),
+                pressure,
+                dry_run,
+            );
+            if enforce && !decision.allowed {
+                allowed = false;
+                retry_after = match (retry_after, decision.retry_after_ms) {
+                    (Some(existing), Some(new_retry)) => Some(existing.max(new_retry)),
+                    (None, Some(new_retry)) => Some(new_retry),
+                    (existing, None) => existing,
+                };
             }
+            if enforce && decision.delay_ms.is_some() {
+                delay = delay.max(decision.delay_ms);
+            }
+            if !decision.allowed {
+                let by = if enforce {
+                    &mut denied_by
+                } else {
+                    &mut annotated_by
+                };
+                by.push(policy.policy_id().to_string());
+            }
+            decisions.insert(policy.policy_id().to_string(), decision);
         }
 
-        self.metrics.record(allowed);
+        if !allowed && !dry_run {
+            // A denied request never runs, so hand back any concurrency slots
+            // it took from policies that admitted it.
+            for (policy, key) in self.policies.iter_mut().zip(keys) {
+                let (Some(key), Some(decision)) = (key, decisions.get_mut(policy.policy_id()))
+                else {
+                    continue;
+                };
+                if let Some((_, grant)) = decision
+                    .concurrency_key
+                    .take()
+                    .as_deref()
+                    .and_then(parse_concurrency_key)
+                {
+                    policy.release_slot(*key, grant);
+                }
+            }
+        }
 
-        if allowed {
+        let mut result = if allowed {
             CheckResult {
                 allowed: true,
-                retry_after_ms: None,
                 decisions,
+                delay_ms: delay,
+                ..CheckResult::default()
             }
         } else {
             CheckResult::denied(retry_after, decisions)
+        };
+        result.first_denied = denied_by.first().cloned();
+        result.denied_by = denied_by;
+        result.annotated_by = annotated_by;
+        result
+    }
+
+    /// Echoes the request id and, unless `dry_run`, counts the result in
+    /// metrics and appends it to the decision log.
+    fn finish(
+        &mut self,
+        request: &CheckRequest,
+        mut result: CheckResult,
+        now_ms: u64,
+        dry_run: bool,
+    ) -> CheckResult {
+        result.request_id = request.request_id.clone();
+        if dry_run {
+            return result;
+        }
+        self.metrics.record(result.allowed);
+        if self.config.decision_log {
+            self.log_decision(request, &result, now_ms);
         }
+        result
     }
 
-    pub fn check_batch(&mut self, requests: Vec<CheckRequest>) -> Vec<CheckResult> {
-        requests.into_iter().map(|req| self.check(req)).collect()
+    /// Counts a check against the instance-wide `maxChecksPerSecond` guard.
+    /// Returns `false` once this second's allowance is used up.
+    fn admit_check(&mut self, now_ms: u64) -> bool {
+        let Some(max) = self.config.max_checks_per_second else {
+            return true;
+        };
+        let second = now_ms / 1000;
+        if self.guard.second != second {
+            self.guard = CheckGuard { second, count: 0 };
+        }
+        if self.guard.count >= max {
+            return false;
+        }
+        self.guard.count += 1;
+        true
+    }
+
+    /// Sheds a check the guard refused. Nothing is evaluated or logged; the
+    /// caller is told to retry at the start of the next second.
+    fn overloaded(&mut self, request: &CheckRequest, now_ms: u64) -> CheckResult {
+        self.metrics.record_overloaded();
+        let retry_after_ms = (1000 - now_ms % 1000) as u32;
+        let mut result = CheckResult::denied(Some(retry_after_ms), IndexMap::new());
+        result.overloaded = true;
+        result.request_id = request.request_id.clone();
+        result
+    }
+
+    /// Decides a request whose evaluation ran out of budget without touching
+    /// any policy state.
+    fn over_budget(&mut self, request: &CheckRequest, now_ms: u64, dry_run: bool) -> CheckResult {
+        let fallback = self
+            .config
+            .evaluation_budget
+            .map(|budget| budget.on_exceeded)
+            .unwrap_or_default();
+        let allowed = fallback == BudgetFallback::Open;
+        if !dry_run {
+            self.metrics.record_budget_exceeded();
+        }
+
+        let mut result = if allowed {
+            CheckResult {
+                allowed: true,
+                ..CheckResult::default()
+            }
+        } else {
+            CheckResult::denied(None, IndexMap::new())
+        };
+        result.budget_exceeded = true;
+        self.finish(request, result, now_ms, dry_run)
+    }
+
+    fn log_decision(&mut self, request: &CheckRequest, result: &CheckResult, now_ms: u64) {
+        let record = DecisionRecord {
+            ts_ms: now_ms,
+            request,
+            result,
+        };
+        let Ok(line) = serde_json::to_string(&record) else {
+            return;
+        };
+        // Keep the newest records when the host falls behind on draining.
+        let max_lines = self.config.decision_log_max_lines as usize;
+        while self.decision_log.len() >= max_lines.max(1) {
+            self.decision_log.pop_front();
+            self.metrics.record_decision_log_dropped();
+        }
+        self.decision_log.push_back(line);
+    }
+
+    /// Takes over the undrained records of the limiter this one replaces,
+    /// dropping (and counting) the oldest if they exceed this one's cap.
+    fn adopt_decision_log(&mut self, previous: &mut Fluxgate) {
+        self.decision_log = std::mem::take(&mut previous.decision_log);
+        let max_lines = (self.config.decision_log_max_lines as usize).max(1);
+        while self.decision_log.len() > max_lines {
+            self.decision_log.pop_front();
+            self.metrics.record_decision_log_dropped();
+        }
+    }
+
+    /// Credits the `cost` of an admitted request back to every policy it
+    /// matches, e.g. when it was cancelled upstream. Returns how many policies
+    /// had state to credit; concurrency slots are returned with `release` or
+    /// `release_key`.
+    pub fn refund(&mut self, mut request: CheckRequest) -> u32 {
+        self.enrich(&mut request);
+        let Some(keys) = self.keys_for(&request, &CheckOptions::default()) else {
+            return 0;
+        };
+        let now_ms = time::now_ms();
+        let cost = request.cost();
+        let pressure = self.pressure;
+        let mut refunded = 0;
+        for (policy, key) in self.policies.iter_mut().zip(keys) {
+            if policy.compiled.definition.max_concurrent.is_some() {
+                continue;
+            }
+            if key.is_some_and(|key| policy.refund(key, now_ms, cost, pressure)) {
+                refunded += 1;
+            }
+        }
+        refunded
+    }
+
+    /// Returns the oldest live slot `request`'s key holds in every
+    /// concurrency policy it matches, for callers that did not keep the
+    /// `concurrencyKey`. Returns how many slots were released.
+    pub fn release(&mut self, mut request: CheckRequest) -> u32 {
+        self.enrich(&mut request);
+        let Some(keys) = self.keys_for(&request, &CheckOptions::default()) else {
+            return 0;
+        };
+        let now_ms = time::now_ms();
+        let mut released = 0;
+        for (policy, key) in self.policies.iter_mut().zip(keys) {
+            if policy.compiled.definition.max_concurrent.is_none() {
+                continue;
+            }
+            if key.is_some_and(|key| policy.release_oldest_slot(key, now_ms)) {
+                released += 1;
+            }
+        }
+        released
+    }
+
+    /// Returns the slot granted by an earlier check under `concurrencyKey`.
+    /// Returns `false` if the key is malformed or its slot was already
+    /// released or expired.
+    pub fn release_key(&mut self, key: &str) -> bool {
+        let Some((key, grant)) = parse_concurrency_key(key) else {
+            return false;
+        };
+        self.policies
+            .iter_mut()
+            .any(|policy| policy.release_slot(key, grant))
+    }
+
+    /// Keys currently serving a `penalty` ban, in policy order and then by
+    /// key, with the hex key format that starts each `concurrencyKey`.
+    pub fn banned_keys(&self) -> Vec<BannedKey> {
+        let now_ms = time::now_ms();
+        let mut banned = Vec::new();
+        for policy in &self.policies {
+            let mut bans: Vec<(u64, u64)> = policy
+                .bans
+                .iter()
+                .filter(|(_, until_ms)| **until_ms > now_ms)
+                .map(|(key, until_ms)| (*key, *until_ms))
+                .collect();
+            bans.sort_unstable();
+            banned.extend(bans.into_iter().map(|(key, until_ms)| BannedKey {
+                policy_id: policy.policy_id().to_string(),
+                key: format!("{key:016x}"),
+                banned_until_ms: until_ms,
+            }));
+        }
+        banned
+    }
+
+    /// Returns every decision recorded since the last drain as JSON Lines and
+    /// clears the buffer. Always empty unless `decisionLog` is enabled; at most
+    /// `decisionLogMaxLines` of the newest records are kept between drains.
+    pub fn drain_decision_log(&mut self) -> String {
+        let mut lines = String::new();
+        for line in self.decision_log.drain(..) {
+            lines.push_str(&line);
+            lines.push('\n');
+        }
+        lines
+    }
+
+    /// Renders the deny template of the first enforcing policy that denied
+    /// `result`. Returns `None` for allowed results or when no denying policy
+    /// has a template.
+    pub fn render_denial(&self, result: &CheckResult) -> Option<DenialResponse> {
+        if result.allowed {
+            return None;
+        }
+        result
+            .decisions
+            .iter()
+            .filter(|(_, decision)| !decision.allowed)
+            .find_map(|(policy_id, _)| {
+                let policy = self
+                    .policies
+                    .iter()
+                    .find(|policy| policy.policy_id() == policy_id)?;
+                if !policy.enforces() {
+                    return None;
+                }
+                denial::render(&policy.compiled.definition, result)
+            })
     }
 
     pub fn rotate(&mut self) {
-        // For the initial WASM build the rotation hook is a lightweight no-op. The
-        // method exists to maintain API compatibility with the native library and
-        // can later incorporate time-sliced eviction when Tier B approximations are
-        // implemented.
+        // Rotation currently only forgets buckets that outlived their policy's
+        // `bucketTtlSeconds`. Time-sliced eviction for Tier B approximations can
+        // hook in here once implemented.
+        let now_ms = time::now_ms();
+        for policy in &mut self.policies {
+            policy.evict_idle(now_ms);
+        }
+    }
+
+    /// Scales every policy's rate, burst and tier limits by `factor` (from
+    /// 0.0, shed everything, to 1.0, the configured limits) until changed
+    /// again. Like environment scaling, any positive factor keeps non-zero
+    /// limits at one or more. Survives `reload` and is part of snapshots.
+    pub fn set_pressure(&mut self, factor: f64) -> Result<()> {
+        if !(0.0..=1.0).contains(&factor) {
+            return Err(FluxgateError::InvalidConfig(format!(
+                "pressure must be between 0.0 and 1.0, got {factor}"
+            )));
+        }
+        self.pressure = factor;
+        Ok(())
+    }
+
+    /// Replaces the `overrides` of each listed policy, keyed by policy id,
+    /// without touching any limiter state. Policies that are not listed keep
+    /// their overrides; nothing changes if any entry is invalid. `reload`
+    /// replaces these with the overrides of the new config.
+    pub fn set_overrides(&mut self, overrides: IndexMap<String, Vec<LimitOverride>>) -> Result<()> {
+        let mut resolved = Vec::with_capacity(overrides.len());
+        for (policy_id, policy_overrides) in overrides {
+            let idx = self
+                .policies
+                .iter()
+                .position(|policy| policy.policy_id() == policy_id)
+                .ok_or_else(|| {
+                    FluxgateError::InvalidConfig(format!("unknown policy {policy_id}"))
+                })?;
+            let mut compiled = self.policies[idx].compiled.clone();
+            compiled.definition.overrides = policy_overrides;
+            let key_limits = PolicyState::key_limits(&compiled, &self.key_builder)?;
+            resolved.push((idx, compiled, key_limits));
+        }
+        for (idx, compiled, key_limits) in resolved {
+            self.config.policies[idx] = compiled.clone();
+            self.policies[idx].compiled = compiled;
+            self.policies[idx].overrides = key_limits;
+        }
+        Ok(())
     }
 
     pub fn reload(&mut self, init: FluxgateInit) -> Result<()> {
         let config = init.into_config()?;
-        let rebuilt = Self::from_config(config)?;
+        let mut rebuilt = Self::from_config(config)?;
+        rebuilt.pressure = self.pressure;
+        let now_ms = time::now_ms();
+        for policy in &mut rebuilt.policies {
+            let Some(previous) = self
+                .policies
+                .iter_mut()
+                .find(|previous| previous.policy_id() == policy.policy_id())
+            else {
+                continue;
+            };
+            // Grant ids keep counting so that a `concurrencyKey` handed out
+            // before the reload can never free a slot granted after it.
+            policy.next_grant = previous.next_grant;
+            if policy.compiled.definition.max_concurrent.is_some() {
+                policy.in_flight = std::mem::take(&mut previous.in_flight);
+            }
+            if policy.compiled.definition.penalty.is_some() {
+                policy.bans = std::mem::take(&mut previous.bans);
+                policy.strikes = std::mem::take(&mut previous.strikes);
+            }
+            let Some(seconds) = policy.compiled.definition.transition_seconds else {
+                continue;
+            };
+            // Start from whatever the previous config was enforcing, which may
+            // itself be partway through a ramp.
+            let (from_limit_per_second, from_burst) = previous.effective_limits(now_ms);
+            policy.transition = Some(LimitTransition {
+                from_limit_per_second,
+                from_burst,
+                start_ms: now_ms,
+                duration_ms: u64::from(seconds) * 1000,
+            });
+        }
+        rebuilt.adopt_decision_log(self);
         *self = rebuilt;
         Ok(())
     }
 
+    /// Serializes the full limiter state. Equal states always produce equal
+    /// bytes, on native and wasm32 alike; see `snapshot` for the format.
     pub fn snapshot(&self) -> Result<Vec<u8>> {
-        bincode::serialize(self).map_err(|err| FluxgateError::Serialization(err.to_string()))
+        snapshot::encode(self)
     }
 
     pub fn restore(&mut self, bytes: &[u8]) -> Result<()> {
-        let restored: Fluxgate = bincode::deserialize(bytes)
-            .map_err(|err| FluxgateError::Serialization(err.to_string()))?;
+        let mut restored: Fluxgate = snapshot::decode(bytes)?;
+        restored.adopt_decision_log(self);
         *self = restored;
         Ok(())
     }
 
+    /// Drops all bucket state and releases its memory. The limiter stays usable
+    /// and behaves as if every key were seen for the first time, except that
+    /// concurrency slots still held stay taken until released or expired, and
+    /// penalty bans and strikes are kept until they lapse.
+    pub fn dispose(&mut self) {
+        for policy in &mut self.policies {
+            policy.buckets = HashMap::new();
+            policy.quotas = HashMap::new();
+            policy.tiers = HashMap::new();
+        }
+        self.decision_log = VecDeque::new();
+    }
+
+    /// Approximate heap bytes held by bucket maps and the decision log.
+    pub fn state_bytes(&self) -> usize {
+        let per_bucket = std::mem::size_of::<(u64, BucketState)>();
+        let per_quota = std::mem::size_of::<(u64, QuotaWindow)>();
+        let per_slot = std::mem::size_of::<(u64, Vec<Grant>)>();
+        let per_grant = std::mem::size_of::<Grant>();
+        let per_strike = std::mem::size_of::<(u64, Strikes)>();
+        let per_ban = std::mem::size_of::<(u64, u64)>();
+        let per_tier_key =
+            std::mem::size_of::<(u64, Vec<SlidingWindow>)>() + std::mem::size_of::<SlidingWindow>();
+        let buckets: usize = self
+            .policies
+            .iter()
+            .map(|policy| {
+                let tiers = policy.compiled.definition.tiers.len();
+                policy.buckets.capacity() * per_bucket
+                    + policy.quotas.capacity() * per_quota
+                    + policy.tiers.capacity() * per_tier_key * tiers
+                    + policy.in_flight.capacity() * per_slot
+                    + policy
+                        .in_flight
+                        .values()
+                        .map(|grants| grants.capacity() * per_grant)
+                        .sum::<usize>()
+                    + policy.strikes.capacity() * per_strike
+                    + policy.bans.capacity() * per_ban
+            })
+            .sum();
+        let log = self.decision_log.capacity() * std::mem::size_of::<String>()
+            + self
+                .decision_log
+                .iter()
+                .map(String::capacity)
+                .sum::<usize>();
+        buckets + log
+    }
+
     pub fn metrics(&self) -> IndexMap<String, u64> {
-        self.metrics.as_map()
+        let mut metrics = self.metrics.as_map();
+        if let Some(max) = self.config.max_checks_per_second {
+            metrics.insert("max_checks_per_second".to_string(), u64::from(max));
+        }
+        metrics
     }
 
     pub fn version(&self) -> String {
@@ -119,43 +796,1225 @@ impl Fluxgate {
     }
 }
 
-impl PolicyState {
-    fn new(compiled: CompiledPolicy) -> Self {
+/// Splits a `concurrencyKey` (`{key:016x}-{grant:x}`) into key and grant id.
+fn parse_concurrency_key(input: &str) -> Option<(u64, u64)> {
+    let (key, grant) = input.split_once('-')?;
+    Some((
+        u64::from_str_radix(key, 16).ok()?,
+        u64::from_str_radix(grant, 16).ok()?,
+    ))
+}
+
+/// What is left of the evaluation budget while one request is matched.
+struct Allowance {
+    policies: u32,
+    work: u32,
+}
+
+impl Allowance {
+    fn new(budget: Option<&EvaluationBudget>) -> Self {
         Self {
+            policies: budget
+                .and_then(|budget| budget.max_policies)
+                .unwrap_or(u32::MAX),
+            work: budget
+                .and_then(|budget| budget.max_clauses)
+                .unwrap_or(u32::MAX),
+        }
+    }
+
+    /// Matches `request` against one policy's rule, charging the policy and
+    /// the matching work it actually does.
+    fn matches(
+        &mut self,
+        matcher: &PolicyMatcher,
+        request: &CheckRequest,
+    ) -> std::result::Result<Option<IndexMap<String, String>>, BudgetExhausted> {
+        self.policies = self.policies.checked_sub(1).ok_or(BudgetExhausted)?;
+        matcher.matches_within(request, &mut self.work)
+    }
+}
+
+impl PolicyState {
+    fn new(compiled: CompiledPolicy, key_builder: &KeyBuilder) -> Result<Self> {
+        let overrides = Self::key_limits(&compiled, key_builder)?;
+        Ok(Self {
             compiled,
             buckets: HashMap::new(),
-        }
+            quotas: HashMap::new(),
+            tiers: HashMap::new(),
+            in_flight: HashMap::new(),
+            next_grant: 0,
+            overrides,
+            strikes: HashMap::new(),
+            bans: HashMap::new(),
+            transition: None,
+        })
+    }
+
+    /// Hashes each override's key the same way `key_for` hashes a matching
+    /// request, so overrides are found with a single map lookup.
+    fn key_limits(
+        compiled: &CompiledPolicy,
+        key_builder: &KeyBuilder,
+    ) -> Result<HashMap<u64, KeyLimits>> {
+        let captures = compiled.override_captures()?;
+        Ok(captures
+            .iter()
+            .zip(&compiled.definition.overrides)
+            .map(|(captured, limit_override)| {
+                let key = key_builder.build_key(&compiled.definition.id, captured);
+                let limits = KeyLimits {
+                    limit_per_second: limit_override.limit_per_second,
+                    burst: limit_override.burst,
+                };
+                (key, limits)
+            })
+            .collect())
     }
 
     fn policy_id(&self) -> &str {
         &self.compiled.definition.id
     }
 
-    fn check(
+    /// `limitPerSecond` and `burst` as currently enforced: while a reload
+    /// transition runs they move linearly from the old values to the new ones.
+    fn effective_limits(&self, now_ms: u64) -> (u32, u32) {
+        let definition = &self.compiled.definition;
+        let target = (definition.limit_per_second, definition.burst);
+        let Some(transition) = self.transition else {
+            return target;
+        };
+        let elapsed_ms = now_ms.saturating_sub(transition.start_ms);
+        if elapsed_ms >= transition.duration_ms {
+            return target;
+        }
+        let progress = elapsed_ms as f64 / transition.duration_ms as f64;
+        let lerp = |from: u32, to: u32| {
+            let value = f64::from(from) + (f64::from(to) - f64::from(from)) * progress;
+            value.round() as u32
+        };
+        (
+            lerp(transition.from_limit_per_second, target.0),
+            lerp(transition.from_burst, target.1),
+        )
+    }
+
+    /// Limits for `key`: its override if it has one, otherwise the effective
+    /// limits, then scaled by the instance's load `pressure`.
+    fn key_limits_at(&self, key: u64, now_ms: u64, pressure: f64) -> (u32, u32) {
+        let (mut limit_per_second, mut burst) = self.effective_limits(now_ms);
+        if let Some(limits) = self.overrides.get(&key) {
+            limit_per_second = limits.limit_per_second.unwrap_or(limit_per_second);
+            burst = limits.burst.unwrap_or(burst);
+        }
+        (
+            scale_limit_u32(limit_per_second, pressure),
+            scale_limit_u32(burst, pressure),
+        )
+    }
+
+    fn bucket_ttl_ms(&self) -> Option<u64> {
+        self.compiled
+            .definition
+            .bucket_ttl_seconds
+            .map(|seconds| u64::from(seconds) * 1000)
+    }
+
+    fn evict_idle(&mut self, now_ms: u64) {
+        if let Some(ttl_ms) = self.bucket_ttl_ms() {
+            self.buckets
+                .retain(|_, bucket| bucket.idle_for(now_ms) < ttl_ms);
+            self.tiers.retain(|_, windows| {
+                windows
+                    .iter()
+                    .any(|window| window.idle_for(now_ms) < ttl_ms)
+            });
+        }
+        self.quotas.retain(|_, window| !window.expired(now_ms));
+        let lease_ms = self.lease_ms();
+        self.in_flight.retain(|_, grants| {
+            grants.retain(|grant| now_ms.saturating_sub(grant.acquired_ms) < lease_ms);
+            !grants.is_empty()
+        });
+        self.bans.retain(|_, until_ms| *until_ms > now_ms);
+        if let Some(penalty) = self.compiled.definition.penalty {
+            let window_ms = penalty.window_ms(self.compiled.definition.window_seconds);
+            self.strikes
+                .retain(|_, strikes| now_ms.saturating_sub(strikes.window_start_ms) < window_ms);
+        }
+    }
+
+    /// Checks the key's quota (if any) and then its bucket, or its slot count
+    /// for `maxConcurrent` policies; quota is only used up by requests that
+    /// are admitted. With `dry_run` nothing is stored.
+    fn consume(
         &mut self,
-        key_builder: &KeyBuilder,
-        request: &CheckRequest,
+        key: u64,
         now_ms: u64,
-    ) -> Option<(CheckDecision, bool)> {
-        let captured = self.compiled.matcher.matches(request)?;
-        let key = key_builder.build_key(&self.compiled.definition.id, &captured);
-        let bucket = self
-            .buckets
-            .entry(key)
-            .or_insert_with(|| TokenBucket::new(self.compiled.definition.burst, now_ms));
-        let (allowed, retry_after_ms) = bucket.consume(
-            self.compiled.definition.limit_per_second,
-            self.compiled.definition.burst,
+        cost: f64,
+        priority: Priority,
+        pressure: f64,
+        dry_run: bool,
+    ) -> (CheckDecision, bool) {
+        if let Some(until_ms) = self.bans.get(&key).copied() {
+            if until_ms > now_ms {
+                let wait_ms = until_ms - now_ms;
+                let decision = CheckDecision {
+                    allowed: false,
+                    retry_after_ms: Some(wait_ms.min(u64::from(u32::MAX)) as u32),
+                    banned: true,
+                    ..CheckDecision::default()
+                };
+                return (decision, self.enforces());
+            }
+            if !dry_run {
+                self.bans.remove(&key);
+            }
+        }
+
+        let quota = self.compiled.definition.quota;
+        let mut window = quota.map(|quota| {
+            let mut window = self
+                .quotas
+                .get(&key)
+                .cloned()
+                .unwrap_or_else(|| QuotaWindow::new(quota.period, now_ms));
+            window.roll(quota.period, now_ms);
+            window
+        });
+
+        let exhausted = quota
+            .zip(window.as_ref())
+            .is_some_and(|(quota, window)| window.remaining(&quota) < cost);
+        let mut grant = None;
+        let (allowed, retry_after_ms) = match &window {
+            Some(window) if exhausted => {
+                let wait_ms = window.reset_ms().saturating_sub(now_ms);
+                (false, Some(wait_ms.min(u64::from(u32::MAX)) as u32))
+            }
+            _ => match self.compiled.definition.max_concurrent {
+                Some(max_concurrent) => {
+                    grant = self.acquire_slot(key, max_concurrent, now_ms, dry_run);
+                    (grant.is_some(), None)
+                }
+                None => self.consume_bucket(key, now_ms, cost, priority, pressure, dry_run),
+            },
+        };
+
+        // An admitted request's hint is the leaky bucket's shaping delay.
+        let mut decision = if allowed {
+            CheckDecision {
+                allowed,
+                delay_ms: retry_after_ms,
+                ..CheckDecision::default()
+            }
+        } else {
+            CheckDecision {
+                allowed,
+                retry_after_ms,
+                ..CheckDecision::default()
+            }
+        };
+        if allowed && !dry_run && self.compiled.definition.max_concurrent.is_some() {
+            if let Some(grant) = grant {
+                decision.concurrency_key = Some(format!("{key:016x}-{grant:x}"));
+            }
+        }
+        if let (Some(quota), Some(window)) = (quota, window.as_mut()) {
+            if allowed {
+                window.record(cost);
+            }
+            decision.quota_remaining = Some(window.remaining(&quota));
+            decision.quota_reset_ms = Some(window.reset_ms());
+            if !dry_run {
+                self.quotas.insert(key, window.clone());
+            }
+        }
+        if !allowed && !dry_run {
+            self.record_strike(key, now_ms);
+        }
+        (decision, self.enforces())
+    }
+
+    /// Counts a denial against the key's `penalty` and bans the key once the
+    /// threshold is reached within the window.
+    fn record_strike(&mut self, key: u64, now_ms: u64) {
+        let Some(penalty) = self.compiled.definition.penalty else {
+            return;
+        };
+        let window_ms = penalty.window_ms(self.compiled.definition.window_seconds);
+        let strikes = self.strikes.entry(key).or_insert(Strikes {
+            count: 0,
+            window_start_ms: now_ms,
+        });
+        if now_ms.saturating_sub(strikes.window_start_ms) >= window_ms {
+            *strikes = Strikes {
+                count: 0,
+                window_start_ms: now_ms,
+            };
+        }
+        strikes.count += 1;
+        if strikes.count >= penalty.threshold_denials {
+            self.strikes.remove(&key);
+            self.bans.insert(key, now_ms + penalty.ban_ms());
+        }
+    }
+
+    /// Debits the key's bucket and every tier window. Nothing is debited
+    /// unless all of them admit the request, and the strictest failing limit
+    /// sets the retry hint; admitted requests carry the bucket's shaping delay,
+    /// if any, instead. With `dry_run` the stored state is left untouched.
+    fn consume_bucket(
+        &mut self,
+        key: u64,
+        now_ms: u64,
+        cost: f64,
+        priority: Priority,
+        pressure: f64,
+        dry_run: bool,
+    ) -> (bool, Option<u32>) {
+        let (limit_per_second, burst) = self.key_limits_at(key, now_ms, pressure);
+        let definition = &self.compiled.definition;
+        let reserve = definition
+            .priority_reserve
+            .map_or(0.0, |reserve| reserve.for_priority(priority));
+        let window_seconds = definition.window_seconds;
+        let algorithm = definition.algorithm.unwrap_or_default();
+        let ttl_ms = self.bucket_ttl_ms();
+        // Expired state is dropped on access too, so a key never inherits
+        // history older than the TTL even if `rotate` has not run.
+        let expired = |idle_ms: u64| ttl_ms.is_some_and(|ttl_ms| idle_ms >= ttl_ms);
+
+        let mut bucket = match self.buckets.get(&key) {
+            Some(bucket) if !expired(bucket.idle_for(now_ms)) => bucket.clone(),
+            _ => BucketState::new(algorithm, burst, window_seconds, now_ms),
+        };
+        let (bucket_allowed, mut retry_after_ms) = bucket.consume(
+            limit_per_second,
+            burst,
+            window_seconds,
+            cost,
+            reserve,
             now_ms,
         );
-        let decision = CheckDecision {
-            allowed,
-            retry_after_ms,
+        let mut allowed = bucket_allowed;
+
+        let stored_windows = self.tiers.get(&key).filter(|windows| {
+            windows
+                .iter()
+                .any(|window| !expired(window.idle_for(now_ms)))
+        });
+        let previous: Vec<SlidingWindow> = match stored_windows {
+            Some(windows) => windows.clone(),
+            None => definition
+                .tiers
+                .iter()
+                .map(|tier| SlidingWindow::new(tier.window_ms(), now_ms))
+                .collect(),
         };
-        let enforce = matches!(
+        let mut windows = previous.clone();
+        let mut tier_admitted = Vec::with_capacity(windows.len());
+        for (tier, window) in definition.tiers.iter().zip(&mut windows) {
+            let tier_limit = scale_limit_u32(tier.limit, pressure);
+            let tier_reserve = f64::from(tier_limit) * reserve;
+            let (tier_allowed, tier_retry) =
+                window.consume(tier_limit, tier.window_ms(), cost, tier_reserve, now_ms);
+            tier_admitted.push(tier_allowed);
+            if !tier_allowed {
+                retry_after_ms = match (allowed, retry_after_ms, tier_retry) {
+                    (false, Some(existing), Some(tier_retry)) => Some(existing.max(tier_retry)),
+                    (false, existing, None) => existing,
+                    (_, _, tier_retry) => tier_retry,
+                };
+                allowed = false;
+            }
+        }
+
+        if dry_run {
+            return (allowed, retry_after_ms);
+        }
+        // On denial only the limits that themselves denied keep their (merely
+        // refreshed) state; the ones that admitted are rolled back.
+        if allowed || !bucket_allowed {
+            self.buckets.insert(key, bucket);
+        }
+        if !windows.is_empty() {
+            if !allowed {
+                for ((window, previous), admitted) in
+                    windows.iter_mut().zip(previous).zip(tier_admitted)
+                {
+                    if admitted {
+                        *window = previous;
+                    }
+                }
+            }
+            self.tiers.insert(key, windows);
+        }
+        (allowed, retry_after_ms)
+    }
+
+    /// Credits `cost` back to the key's bucket, tier windows and current quota
+    /// period. Returns whether the key had any state to credit.
+    fn refund(&mut self, key: u64, now_ms: u64, cost: f64, pressure: f64) -> bool {
+        let (limit_per_second, burst) = self.key_limits_at(key, now_ms, pressure);
+        let definition = &self.compiled.definition;
+        let mut credited = false;
+        if let Some(bucket) = self.buckets.get_mut(&key) {
+            bucket.refund(
+                limit_per_second,
+                burst,
+                definition.window_seconds,
+                cost,
+                now_ms,
+            );
+            credited = true;
+        }
+        if let Some(windows) = self.tiers.get_mut(&key) {
+            for (tier, window) in definition.tiers.iter().zip(windows) {
+                window.refund(tier.window_ms(), cost, now_ms);
+            }
+            credited = true;
+        }
+        if let Some(window) = self.quotas.get_mut(&key) {
+            if !window.expired(now_ms) {
+                window.refund(cost);
+                credited = true;
+            }
+        }
+        credited
+    }
+
+    fn lease_ms(&self) -> u64 {
+        u64::from(
+            self.compiled
+                .definition
+                .lease_seconds
+                .unwrap_or(DEFAULT_LEASE_SECONDS),
+        ) * 1000
+    }
+
+    /// Takes one of the key's `max_concurrent` slots after dropping grants
+    /// whose lease ran out, returning the new grant's id (0 for dry runs).
+    /// Concurrency denials carry no retry hint since slots free up only when
+    /// callers release them or their leases expire.
+    fn acquire_slot(
+        &mut self,
+        key: u64,
+        max_concurrent: u32,
+        now_ms: u64,
+        dry_run: bool,
+    ) -> Option<u64> {
+        let lease_ms = self.lease_ms();
+        let live = |grant: &Grant| now_ms.saturating_sub(grant.acquired_ms) < lease_ms;
+        if dry_run {
+            let held = self.in_flight.get(&key).map_or(0, |grants| {
+                grants.iter().filter(|grant| live(grant)).count()
+            });
+            return (held < max_concurrent as usize).then_some(0);
+        }
+        let grants = self.in_flight.entry(key).or_default();
+        grants.retain(live);
+        if grants.len() >= max_concurrent as usize {
+            return None;
+        }
+        let id = self.next_grant;
+        self.next_grant += 1;
+        grants.push(Grant {
+            id,
+            acquired_ms: now_ms,
+        });
+        Some(id)
+    }
+
+    fn release_oldest_slot(&mut self, key: u64, now_ms: u64) -> bool {
+        let lease_ms = self.lease_ms();
+        let oldest = self.in_flight.get(&key).and_then(|grants| {
+            grants
+                .iter()
+                .filter(|grant| now_ms.saturating_sub(grant.acquired_ms) < lease_ms)
+                .min_by_key(|grant| grant.id)
+                .map(|grant| grant.id)
+        });
+        oldest.is_some_and(|grant| self.release_slot(key, grant))
+    }
+
+    fn release_slot(&mut self, key: u64, grant: u64) -> bool {
+        let Some(grants) = self.in_flight.get_mut(&key) else {
+            return false;
+        };
+        let Some(index) = grants.iter().position(|held| held.id == grant) else {
+            return false;
+        };
+        grants.swap_remove(index);
+        if grants.is_empty() {
+            self.in_flight.remove(&key);
+        }
+        true
+    }
+
+    fn enforces(&self) -> bool {
+        matches!(
             self.compiled.definition.action,
             None | Some(PolicyAction::Reject)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Fluxgate;
+    use crate::bucket::BucketState;
+    use crate::config::{
+        CheckOptions, CheckRequest, CheckResult, FluxgateInit, LimitOverride, Priority,
+    };
+    use crate::time;
+    use indexmap::IndexMap;
+
+    fn gate(policies: serde_json::Value) -> Fluxgate {
+        let init: FluxgateInit =
+            serde_json::from_value(serde_json::json!({ "policies": policies })).unwrap();
+        Fluxgate::new(init).unwrap()
+    }
+
+    fn ip(addr: &str) -> CheckRequest {
+        CheckRequest {
+            ip: Some(addr.to_string()),
+            ..CheckRequest::default()
+        }
+    }
+
+    fn result_at(gate: &mut Fluxgate, request: CheckRequest, now_ms: u64) -> CheckResult {
+        let keys = gate.keys_for(&request, &CheckOptions::default()).unwrap();
+        gate.apply(&request, &keys, now_ms, false)
+    }
+
+    fn check_at(gate: &mut Fluxgate, request: CheckRequest, now_ms: u64) -> bool {
+        result_at(gate, request, now_ms).allowed
+    }
+
+    #[test]
+    fn dry_run_does_not_consume_tokens() {
+        let mut gate = gate(serde_json::json!([
+            { "id": "ip", "match": "ip:*", "limitPerSecond": 1, "burst": 1, "windowSeconds": 1 }
+        ]));
+        let dry_run = CheckOptions {
+            dry_run: true,
+            ..CheckOptions::default()
+        };
+
+        assert!(gate.check_with(ip("10.0.0.1"), &dry_run).allowed);
+        assert!(gate.check_with(ip("10.0.0.1"), &dry_run).allowed);
+        assert!(gate.check(ip("10.0.0.1")).allowed);
+        assert!(!gate.check_with(ip("10.0.0.1"), &dry_run).allowed);
+        assert_eq!(gate.metrics()["checks_total"], 1);
+    }
+
+    #[test]
+    fn concurrency_slots_are_returned_on_release() {
+        let mut gate = gate(serde_json::json!([
+            { "id": "inflight", "match": "ip:*", "limitPerSecond": 0, "burst": 0,
+              "windowSeconds": 0, "maxConcurrent": 2 },
+            { "id": "never", "match": "ip:10.0.0.9", "limitPerSecond": 0, "burst": 0,
+              "windowSeconds": 0 }
+        ]));
+
+        let slot = |result: CheckResult| result.decisions["inflight"].concurrency_key.clone();
+        let first = slot(gate.check(ip("10.0.0.1"))).unwrap();
+        let second = slot(gate.check(ip("10.0.0.1"))).unwrap();
+        assert_ne!(first, second);
+        assert!(!gate.check(ip("10.0.0.1")).allowed);
+
+        // Each key frees only its own grant, and only once.
+        assert!(gate.release_key(&first));
+        assert!(!gate.release_key(&first));
+        let third = slot(gate.check(ip("10.0.0.1"))).unwrap();
+        assert!(gate.release_key(&second));
+        assert!(gate.release_key(&third));
+
+        // Denied by another policy, so the slot it took is handed back.
+        let denied = gate.check(ip("10.0.0.9"));
+        assert!(!denied.allowed);
+        assert_eq!(slot(denied), None);
+        assert!(gate.check(ip("10.0.0.9")).decisions["inflight"].allowed);
+        assert!(gate.check(ip("10.0.0.9")).decisions["inflight"].allowed);
+        assert!(!gate.release_key("not-a-key"));
+
+        // Releasing by request frees the key's oldest grant, so its own
+        // `concurrencyKey` no longer frees anything.
+        let oldest = slot(gate.check(ip("10.0.0.1"))).unwrap();
+        let newest = slot(gate.check(ip("10.0.0.1"))).unwrap();
+        assert_eq!(gate.release(ip("10.0.0.1")), 1);
+        assert!(!gate.release_key(&oldest));
+        assert!(gate.release_key(&newest));
+        assert_eq!(gate.release(ip("10.0.0.1")), 0);
+    }
+
+    #[test]
+    fn concurrency_slots_expire_after_their_lease() {
+        let mut gate = gate(serde_json::json!([
+            { "id": "inflight", "match": "ip:*", "limitPerSecond": 0, "burst": 0,
+              "windowSeconds": 0, "maxConcurrent": 1, "leaseSeconds": 5 }
+        ]));
+        let start = time::now_ms();
+
+        let held = result_at(&mut gate, ip("10.0.0.1"), start).decisions["inflight"]
+            .concurrency_key
+            .clone()
+            .unwrap();
+        assert!(!check_at(&mut gate, ip("10.0.0.1"), start + 4_999));
+        // The lapsed grant is dropped when the next one is taken.
+        assert!(check_at(&mut gate, ip("10.0.0.1"), start + 5_000));
+        assert!(!gate.release_key(&held));
+
+        // Eviction drops lapsed grants for keys that are not checked again.
+        result_at(&mut gate, ip("10.0.0.2"), start);
+        gate.policies[0].evict_idle(start + 5_000);
+        assert_eq!(gate.policies[0].in_flight.len(), 1);
+        gate.policies[0].evict_idle(start + 10_000);
+        assert!(gate.policies[0].in_flight.is_empty());
+    }
+
+    #[test]
+    fn strictest_failing_tier_denies_without_debiting_others() {
+        let mut gate = gate(serde_json::json!([
+            { "id": "api", "match": "ip:*", "limitPerSecond": 10, "burst": 10, "windowSeconds": 1,
+              "tiers": [ { "limit": 2, "windowSeconds": 60 } ] }
+        ]));
+
+        assert!(gate.check(ip("10.0.0.1")).allowed);
+        assert!(gate.check(ip("10.0.0.1")).allowed);
+        let denied = gate.check(ip("10.0.0.1"));
+        assert!(!denied.allowed);
+        assert!(denied.retry_after_ms.unwrap() > 1_000);
+
+        // The base bucket was only debited by the two admitted requests.
+        let Some(BucketState::TokenBucket(bucket)) = gate.policies[0].buckets.values().next()
+        else {
+            panic!("expected a token bucket");
+        };
+        assert!(bucket.remaining_tokens() > 7.5);
+    }
+
+    #[test]
+    fn snapshot_bytes_do_not_depend_on_insertion_order() {
+        let policies = serde_json::json!([
+            { "id": "ip", "match": "ip:*", "limitPerSecond": 5, "burst": 5, "windowSeconds": 1,
+              "quota": { "limit": 100, "period": "day" } }
+        ]);
+        let mut forward = gate(policies.clone());
+        let mut backward = gate(policies.clone());
+        let addrs: Vec<String> = (0..64).map(|n| format!("10.0.0.{n}")).collect();
+        for addr in &addrs {
+            check_at(&mut forward, ip(addr), 1_000);
+        }
+        for addr in addrs.iter().rev() {
+            check_at(&mut backward, ip(addr), 1_000);
+        }
+
+        let bytes = forward.snapshot().unwrap();
+        assert_eq!(bytes, backward.snapshot().unwrap());
+
+        let mut restored = gate(policies);
+        restored.restore(&bytes).unwrap();
+        assert_eq!(restored.snapshot().unwrap(), bytes);
+        assert!(restored.restore(&bytes[..5]).is_err());
+    }
+
+    #[test]
+    fn parent_applies_only_through_its_children() {
+        let mut gate = gate(serde_json::json!([
+            { "id": "tenant", "match": "header:x-tenant", "limitPerSecond": 1, "burst": 3,
+              "windowSeconds": 60 },
+            { "id": "user", "match": "header:x-tenant header:x-user", "parent": "tenant",
+              "limitPerSecond": 1, "burst": 2, "windowSeconds": 60 }
+        ]));
+        let request = |user: Option<&str>| CheckRequest {
+            headers: Some(
+                [("x-tenant", Some("acme")), ("x-user", user)]
+                    .into_iter()
+                    .map(|(name, value)| (name.to_string(), value.map(str::to_string)))
+                    .collect(),
+            ),
+            ..CheckRequest::default()
+        };
+
+        // Without a user the child does not match, so neither level applies.
+        assert!(gate.check(request(None)).decisions.is_empty());
+
+        assert!(gate.check(request(Some("a"))).allowed);
+        assert!(gate.check(request(Some("a"))).allowed);
+        let denied = gate.check(request(Some("a")));
+        assert!(!denied.decisions["user"].allowed);
+        assert!(denied.decisions["tenant"].allowed);
+        assert_eq!(denied.denied_by, ["user"]);
+
+        // All three tenant tokens are spent, whichever user asks next.
+        let denied = gate.check(request(Some("b")));
+        assert!(denied.decisions["user"].allowed);
+        assert!(!denied.decisions["tenant"].allowed);
+        assert_eq!(denied.first_denied.as_deref(), Some("tenant"));
+    }
+
+    #[test]
+    fn exhausted_budget_decides_without_touching_state() {
+        let budget_gate = |max_clauses: u32| {
+            let init = serde_json::json!({
+                "evaluationBudget": { "maxClauses": max_clauses, "onExceeded": "closed" },
+                "policies": [
+                    { "id": "ip", "match": "ip:*", "limitPerSecond": 1, "burst": 1,
+                      "windowSeconds": 1 },
+                    { "id": "org", "match": "attr:user.org.id=?", "limitPerSecond": 1,
+                      "burst": 1, "windowSeconds": 1 }
+                ]
+            });
+            Fluxgate::new(serde_json::from_value(init).unwrap())
+        };
+        assert!(budget_gate(1).is_err());
+        let mut gate = budget_gate(3).unwrap();
+        let with_attrs = |attrs: serde_json::Value| CheckRequest {
+            attrs: Some(serde_json::from_value(attrs).unwrap()),
+            ..ip("10.0.0.1")
+        };
+
+        // A flat key or a missing `user` stays within the static clause count.
+        let flat = gate.check(with_attrs(serde_json::json!({ "user.org.id": "acme" })));
+        assert!(flat.allowed && !flat.budget_exceeded);
+        let missing = gate.check(with_attrs(serde_json::json!({})));
+        assert!(!missing.budget_exceeded);
+
+        // Walking `user`, `org` and `id` costs three more units than that.
+        let nested = serde_json::json!({ "user": { "org": { "id": "globex" } } });
+        let result = gate.check(with_attrs(nested.clone()));
+        assert!(!result.allowed && result.budget_exceeded);
+        assert!(result.decisions.is_empty());
+        assert_eq!(gate.policies[1].buckets.len(), 1);
+
+        let skip_org = CheckOptions {
+            skip_policies: vec!["org".to_string()],
+            ..CheckOptions::default()
+        };
+        assert!(
+            !gate
+                .check_with(with_attrs(nested), &skip_org)
+                .budget_exceeded
         );
-        Some((decision, enforce))
+        assert_eq!(gate.metrics()["budget_exceeded_total"], 1);
+    }
+
+    #[test]
+    fn check_n_consumes_all_units_or_none() {
+        let mut gate = gate(serde_json::json!([
+            { "id": "ip", "match": "ip:*", "limitPerSecond": 1, "burst": 5, "windowSeconds": 60 },
+            { "id": "login", "match": "ip:* route:/login", "limitPerSecond": 1, "burst": 2,
+              "windowSeconds": 60 }
+        ]));
+        let login = CheckRequest {
+            route: Some("/login".to_string()),
+            ..ip("10.0.0.1")
+        };
+
+        // `login` cannot fit 3 units, so `ip` must not be debited either.
+        assert!(!gate.check_n(login.clone(), 3).unwrap().allowed);
+        assert!(gate.peek(ip("10.0.0.1")).allowed);
+        assert!(gate.check_n(ip("10.0.0.1"), 5).unwrap().allowed);
+        assert!(!gate.peek(ip("10.0.0.1")).allowed);
+        assert_eq!(gate.metrics()["checks_total"], 2);
+
+        assert!(gate.check_n(ip("10.0.0.1"), 0).is_err());
+        assert_eq!(gate.metrics()["checks_total"], 2);
+    }
+
+    #[test]
+    fn check_n_denials_count_towards_penalties() {
+        let mut gate = gate(serde_json::json!([
+            { "id": "ip", "match": "ip:*", "limitPerSecond": 1, "burst": 5, "windowSeconds": 60 },
+            { "id": "login", "match": "ip:* route:/login", "limitPerSecond": 1, "burst": 2,
+              "windowSeconds": 60, "penalty": { "thresholdDenials": 2, "banSeconds": 600 } }
+        ]));
+        let login = CheckRequest {
+            route: Some("/login".to_string()),
+            ..ip("10.0.0.1")
+        };
+
+        assert!(!gate.check_n(login.clone(), 3).unwrap().allowed);
+        assert!(gate.banned_keys().is_empty());
+        assert!(!gate.check_n(login.clone(), 3).unwrap().allowed);
+        let banned = gate.banned_keys();
+        assert_eq!(banned.len(), 1);
+        assert_eq!(banned[0].policy_id, "login");
+
+        // Now even a request that fits is turned away by the ban.
+        let denied = gate.check_n(login, 1).unwrap();
+        assert!(denied.decisions["login"].banned);
+    }
+
+    #[test]
+    fn reload_ramps_limits_over_transition() {
+        let mut gate = gate(serde_json::json!([
+            { "id": "ip", "match": "ip:*", "limitPerSecond": 100, "burst": 200, "windowSeconds": 1 }
+        ]));
+        let lowered: FluxgateInit = serde_json::from_value(serde_json::json!({ "policies": [
+            { "id": "ip", "match": "ip:*", "limitPerSecond": 10, "burst": 20, "windowSeconds": 1,
+              "transitionSeconds": 10 }
+        ]}))
+        .unwrap();
+        gate.reload(lowered).unwrap();
+
+        let policy = &gate.policies[0];
+        let start_ms = policy.transition.unwrap().start_ms;
+        assert_eq!(policy.effective_limits(start_ms), (100, 200));
+        assert_eq!(policy.effective_limits(start_ms + 5_000), (55, 110));
+        assert_eq!(policy.effective_limits(start_ms + 10_000), (10, 20));
+    }
+
+    #[test]
+    fn refund_credits_tokens_up_to_burst() {
+        let mut gate = gate(serde_json::json!([
+            { "id": "ip", "match": "ip:*", "limitPerSecond": 1, "burst": 2, "windowSeconds": 60,
+              "quota": { "limit": 2, "period": "day" } }
+        ]));
+
+        assert!(gate.check(ip("10.0.0.1")).allowed);
+        assert!(gate.check(ip("10.0.0.1")).allowed);
+        assert!(!gate.check(ip("10.0.0.1")).allowed);
+
+        assert_eq!(gate.refund(ip("10.0.0.1")), 1);
+        assert_eq!(gate.refund(ip("10.0.0.2")), 0);
+        let result = gate.check(ip("10.0.0.1"));
+        assert!(result.allowed);
+        assert_eq!(result.decisions["ip"].quota_remaining, Some(0.0));
+    }
+
+    #[test]
+    fn guard_sheds_checks_beyond_instance_limit() {
+        let init = serde_json::json!({
+            "maxChecksPerSecond": 2,
+            "policies": [
+                { "id": "ip", "match": "ip:*", "limitPerSecond": 100, "burst": 100, "windowSeconds": 1 }
+            ]
+        });
+
+        // The guard counts per wall-clock second, so retry if the checks below
+        // happen to straddle a second boundary.
+        for _ in 0..5 {
+            let mut gate = Fluxgate::new(serde_json::from_value(init.clone()).unwrap()).unwrap();
+            let second = time::now_ms() / 1000;
+            let peeks: Vec<CheckResult> = (0..3).map(|_| gate.peek(ip("10.0.0.1"))).collect();
+            let batch = gate.check_batch(vec![ip("10.0.0.1"), ip("10.0.0.2"), ip("10.0.0.3")]);
+            let single = gate.check(ip("10.0.0.1"));
+            if time::now_ms() / 1000 != second {
+                continue;
+            }
+
+            assert!(peeks.iter().all(|peek| peek.allowed && !peek.overloaded));
+            assert!(batch[0].allowed && batch[1].allowed);
+            assert!(batch[2].overloaded && !batch[2].allowed);
+            assert!(single.overloaded && single.decisions.is_empty());
+            assert!(single
+                .retry_after_ms
+                .is_some_and(|retry_ms| retry_ms <= 1_000));
+            let metrics = gate.metrics();
+            assert_eq!(metrics["max_checks_per_second"], 2);
+            assert_eq!(metrics["overloaded_total"], 2);
+            assert_eq!(metrics["checks_total"], 2);
+            return;
+        }
+        panic!("every attempt straddled a second boundary");
+    }
+
+    #[test]
+    fn low_priority_leaves_reserved_capacity() {
+        let mut gate = gate(serde_json::json!([
+            { "id": "api", "match": "ip:*", "limitPerSecond": 1, "burst": 10, "windowSeconds": 60,
+              "priorityReserve": { "normal": 0.1, "low": 0.3 } }
+        ]));
+        let with_priority = |priority: Priority| CheckRequest {
+            priority: Some(priority),
+            ..ip("10.0.0.1")
+        };
+
+        let low_admitted = (0..10)
+            .take_while(|_| gate.check(with_priority(Priority::Low)).allowed)
+            .count();
+        assert_eq!(low_admitted, 7);
+        assert!(gate.check(with_priority(Priority::Normal)).allowed);
+        assert!(gate.check(with_priority(Priority::Normal)).allowed);
+        assert!(!gate.check(with_priority(Priority::Normal)).allowed);
+        assert!(gate.check(with_priority(Priority::High)).allowed);
+    }
+
+    #[test]
+    fn pressure_scales_limits_and_survives_reload() {
+        let policies = serde_json::json!([
+            { "id": "ip", "match": "ip:*", "limitPerSecond": 10, "burst": 10, "windowSeconds": 60 }
+        ]);
+        let mut gate = gate(policies.clone());
+        assert!(gate.set_pressure(1.5).is_err());
+        gate.set_pressure(0.2).unwrap();
+
+        assert!(gate.check(ip("10.0.0.1")).allowed);
+        assert!(gate.check(ip("10.0.0.1")).allowed);
+        assert!(!gate.check(ip("10.0.0.1")).allowed);
+
+        let init: FluxgateInit =
+            serde_json::from_value(serde_json::json!({ "policies": policies })).unwrap();
+        gate.reload(init).unwrap();
+        assert_eq!(gate.pressure, 0.2);
+
+        // A small factor throttles to a single token rather than closing.
+        gate.set_pressure(0.04).unwrap();
+        assert!(gate.check(ip("10.0.0.2")).allowed);
+        let throttled = gate.check(ip("10.0.0.2"));
+        assert!(!throttled.allowed && throttled.retry_after_ms.is_some());
+        gate.set_pressure(0.0).unwrap();
+        assert!(!gate.check(ip("10.0.0.3")).allowed);
+    }
+
+    #[test]
+    fn overrides_apply_to_their_key_only_and_can_be_replaced() {
+        let mut gate = gate(serde_json::json!([{
+            "id": "api",
+            "match": "header:x-api-key",
+            "limitPerSecond": 1,
+            "burst": 1,
+            "windowSeconds": 60,
+            "overrides": [{ "key": { "header:x-api-key": "acme" }, "burst": 3 }]
+        }]));
+        let with_key = |key: &str| CheckRequest {
+            headers: Some(IndexMap::from([(
+                "x-api-key".to_string(),
+                Some(key.to_string()),
+            )])),
+            ..CheckRequest::default()
+        };
+
+        let admitted = |gate: &mut Fluxgate, key: &str, now_ms: u64| {
+            (0..5)
+                .filter(|_| check_at(gate, with_key(key), now_ms))
+                .count()
+        };
+        assert_eq!(admitted(&mut gate, "acme", 0), 3);
+        assert_eq!(admitted(&mut gate, "other", 0), 1);
+
+        let replaced: IndexMap<String, Vec<LimitOverride>> = serde_json::from_value(
+            serde_json::json!({ "api": [{ "key": { "header:x-api-key": "beta" }, "burst": 2 }] }),
+        )
+        .unwrap();
+        gate.set_overrides(replaced).unwrap();
+        assert_eq!(admitted(&mut gate, "beta", 0), 2);
+
+        let invalid: IndexMap<String, Vec<LimitOverride>> = serde_json::from_value(
+            serde_json::json!({ "api": [{ "key": { "ip": "10.0.0.1" }, "burst": 2 }] }),
+        )
+        .unwrap();
+        assert!(gate.set_overrides(invalid).is_err());
+        // The rejected call left the previous overrides in place.
+        assert_eq!(admitted(&mut gate, "beta", 60_000), 2);
+        assert_eq!(admitted(&mut gate, "acme", 60_000), 1);
+
+        // Overrides installed at runtime survive a snapshot round trip.
+        let mut restored = gate.clone();
+        restored.restore(&gate.snapshot().unwrap()).unwrap();
+        assert_eq!(admitted(&mut restored, "beta", 120_000), 2);
+        assert_eq!(admitted(&mut restored, "acme", 120_000), 1);
+    }
+
+    #[test]
+    fn repeated_denials_ban_the_key_across_snapshots() {
+        let login = |penalty: serde_json::Value| {
+            serde_json::json!([{
+                "id": "login",
+                "match": "ip:*",
+                "limitPerSecond": 1,
+                "burst": 1,
+                "windowSeconds": 60,
+                "penalty": penalty
+            }])
+        };
+        let penalty = serde_json::json!({ "thresholdDenials": 2, "banSeconds": 600 });
+        let mut gate = gate(login(penalty.clone()));
+        let now_ms = time::now_ms();
+
+        assert!(check_at(&mut gate, ip("10.0.0.1"), now_ms));
+        assert!(!check_at(&mut gate, ip("10.0.0.1"), now_ms));
+        assert!(gate.banned_keys().is_empty());
+        assert!(!check_at(&mut gate, ip("10.0.0.1"), now_ms));
+
+        let banned = gate.banned_keys();
+        assert_eq!(banned.len(), 1);
+        assert_eq!(banned[0].policy_id, "login");
+        assert_eq!(banned[0].banned_until_ms, now_ms + 600_000);
+
+        let mut restored = gate.clone();
+        restored.dispose();
+        restored.restore(&gate.snapshot().unwrap()).unwrap();
+        assert_eq!(restored.banned_keys().len(), 1);
+
+        // Refilled tokens do not lift the ban; it expires on its own.
+        assert!(!check_at(&mut restored, ip("10.0.0.1"), now_ms + 5_000));
+        assert!(check_at(&mut restored, ip("10.0.0.1"), now_ms + 600_000));
+
+        // Bans and strikes also outlive `dispose` and a reload that keeps the
+        // penalty; dropping the penalty lifts them.
+        let reload = |gate: &mut Fluxgate, penalty: serde_json::Value| {
+            let init = serde_json::json!({ "policies": login(penalty) });
+            gate.reload(serde_json::from_value(init).unwrap()).unwrap();
+        };
+        assert!(check_at(&mut gate, ip("10.0.0.2"), now_ms));
+        assert!(!check_at(&mut gate, ip("10.0.0.2"), now_ms));
+        gate.dispose();
+        reload(&mut gate, penalty);
+        assert_eq!(gate.banned_keys().len(), 1);
+        // The fresh bucket admits once; the next denial is the second strike.
+        assert!(check_at(&mut gate, ip("10.0.0.2"), now_ms + 10));
+        assert!(!check_at(&mut gate, ip("10.0.0.2"), now_ms + 10));
+        assert_eq!(gate.banned_keys().len(), 2);
+        reload(&mut gate, serde_json::Value::Null);
+        assert!(gate.banned_keys().is_empty());
+    }
+
+    #[test]
+    fn retry_after_covers_the_whole_cost() {
+        for algorithm in ["token_bucket", "gcra", "sliding_window"] {
+            let mut gate = gate(serde_json::json!([
+                { "id": "api", "match": "ip:*", "limitPerSecond": 1, "burst": 4, "windowSeconds": 4,
+                  "algorithm": algorithm }
+            ]));
+            let costly = |cost: f64| CheckRequest {
+                cost: Some(cost),
+                ..ip("10.0.0.1")
+            };
+
+            assert!(check_at(&mut gate, costly(3.0), 10_000), "{algorithm}");
+            let denied = result_at(&mut gate, costly(3.0), 10_000);
+            assert!(!denied.allowed, "{algorithm}");
+            let retry_ms = u64::from(denied.retry_after_ms.unwrap());
+            assert!(retry_ms >= 2_000, "{algorithm}: {retry_ms}");
+
+            assert!(
+                !check_at(&mut gate, costly(3.0), 10_000 + retry_ms - 100),
+                "{algorithm}"
+            );
+            assert!(
+                check_at(&mut gate, costly(3.0), 10_000 + retry_ms),
+                "{algorithm}"
+            );
+            assert!(!check_at(&mut gate, costly(5.0), 60_000), "{algorithm}");
+        }
+
+        // The leaky bucket queues instead, so it denies only once the queue
+        // ahead would outlast `burst`, and never admits more than `burst` at once.
+        let mut gate = gate(serde_json::json!([
+            { "id": "api", "match": "ip:*", "limitPerSecond": 1, "burst": 4, "windowSeconds": 4,
+              "algorithm": "leaky_bucket" }
+        ]));
+        let costly = |cost: f64| CheckRequest {
+            cost: Some(cost),
+            ..ip("10.0.0.1")
+        };
+        assert!(check_at(&mut gate, costly(3.0), 10_000));
+        assert!(check_at(&mut gate, costly(3.0), 10_000));
+        let denied = result_at(&mut gate, costly(3.0), 10_000);
+        assert_eq!(denied.retry_after_ms, Some(2_000));
+        assert!(!check_at(&mut gate, costly(3.0), 11_900));
+        assert!(check_at(&mut gate, costly(3.0), 12_000));
+
+        for cost in [5.0, 1e12] {
+            let oversized = result_at(&mut gate, costly(cost), 60_000);
+            assert!(!oversized.allowed && oversized.retry_after_ms.is_none());
+        }
+        assert!(check_at(&mut gate, costly(4.0), 60_000));
+    }
+
+    #[test]
+    fn quota_is_used_up_by_cost() {
+        let mut gate = gate(serde_json::json!([
+            { "id": "api", "match": "ip:*", "limitPerSecond": 100, "burst": 100, "windowSeconds": 1,
+              "quota": { "limit": 5, "period": "day" } }
+        ]));
+        let costly = |cost: f64| CheckRequest {
+            cost: Some(cost),
+            ..ip("10.0.0.1")
+        };
+
+        let admitted = result_at(&mut gate, costly(3.0), 1_000);
+        assert_eq!(admitted.decisions["api"].quota_remaining, Some(2.0));
+        let denied = result_at(&mut gate, costly(3.0), 1_000);
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after_ms, Some(86_400_000 - 1_000));
+        assert!(check_at(&mut gate, costly(2.0), 1_000));
+    }
+
+    #[test]
+    fn delay_is_the_longest_among_enforcing_policies() {
+        let mut gate = gate(serde_json::json!([
+            { "id": "fast", "match": "ip:*", "limitPerSecond": 10, "burst": 5, "windowSeconds": 1,
+              "algorithm": "leaky_bucket" },
+            { "id": "slow", "match": "ip:*", "limitPerSecond": 4, "burst": 5, "windowSeconds": 1,
+              "algorithm": "leaky_bucket" },
+            { "id": "audit", "match": "ip:*", "limitPerSecond": 1, "burst": 5, "windowSeconds": 1,
+              "algorithm": "leaky_bucket", "action": "annotate" }
+        ]));
+
+        let first = result_at(&mut gate, ip("10.0.0.1"), 1_000);
+        assert!(first.allowed);
+        assert_eq!(first.delay_ms, None);
+        assert!(first
+            .decisions
+            .values()
+            .all(|decision| decision.delay_ms.is_none()));
+
+        let second = result_at(&mut gate, ip("10.0.0.1"), 1_000);
+        assert_eq!(second.decisions["fast"].delay_ms, Some(100));
+        assert_eq!(second.decisions["slow"].delay_ms, Some(250));
+        assert_eq!(second.decisions["audit"].delay_ms, Some(1_000));
+        assert_eq!(second.delay_ms, Some(250));
+    }
+
+    #[test]
+    fn decision_log_keeps_the_newest_lines_up_to_its_cap() {
+        let init = serde_json::json!({
+            "decisionLog": true,
+            "decisionLogMaxLines": 2,
+            "policies": [
+                { "id": "ip", "match": "ip:*", "limitPerSecond": 100, "burst": 100, "windowSeconds": 1 }
+            ]
+        });
+        let mut gate = Fluxgate::new(serde_json::from_value(init).unwrap()).unwrap();
+
+        for n in 1..=3 {
+            gate.check(CheckRequest {
+                request_id: Some(format!("req-{n}")),
+                ..ip("10.0.0.1")
+            });
+        }
+        let drained = gate.drain_decision_log();
+        let ids: Vec<String> = drained
+            .lines()
+            .map(|line| {
+                let record: serde_json::Value = serde_json::from_str(line).unwrap();
+                record["request"]["requestId"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(ids, ["req-2", "req-3"]);
+        assert!(drained.ends_with('\n'));
+        assert_eq!(gate.metrics()["decision_log_dropped_total"], 1);
+        assert_eq!(gate.drain_decision_log(), "");
+
+        // Dry runs decide nothing, so they are left out of the log.
+        gate.peek(ip("10.0.0.1"));
+        assert_eq!(gate.drain_decision_log(), "");
+
+        // Undrained records outlive a restore and a reload to a smaller cap.
+        let snapshot = gate.snapshot().unwrap();
+        for n in 4..=5 {
+            gate.check(CheckRequest {
+                request_id: Some(format!("req-{n}")),
+                ..ip("10.0.0.1")
+            });
+        }
+        gate.restore(&snapshot).unwrap();
+        let smaller = serde_json::json!({
+            "decisionLog": true,
+            "decisionLogMaxLines": 1,
+            "policies": [
+                { "id": "ip", "match": "ip:*", "limitPerSecond": 100, "burst": 100, "windowSeconds": 1 }
+            ]
+        });
+        gate.reload(serde_json::from_value(smaller).unwrap())
+            .unwrap();
+        let drained = gate.drain_decision_log();
+        assert_eq!(drained.lines().count(), 1);
+        assert!(drained.contains("req-5"));
+        assert_eq!(gate.metrics()["decision_log_dropped_total"], 1);
+    }
+
+    #[test]
+    fn idle_state_expires_after_bucket_ttl() {
+        let mut gate = gate(serde_json::json!([
+            { "id": "api", "match": "ip:*", "limitPerSecond": 100, "burst": 100, "windowSeconds": 1,
+              "tiers": [ { "limit": 1, "windowSeconds": 3600 } ], "bucketTtlSeconds": 10 }
+        ]));
+        let now_ms = time::now_ms();
+
+        // Expired on access: the hourly tier forgets a key idle for the TTL.
+        let start_ms = now_ms - 60_000;
+        assert!(check_at(&mut gate, ip("10.0.0.1"), start_ms));
+        assert!(!check_at(&mut gate, ip("10.0.0.1"), start_ms + 1_000));
+        assert!(!check_at(&mut gate, ip("10.0.0.1"), start_ms + 10_999));
+        assert!(check_at(&mut gate, ip("10.0.0.1"), start_ms + 21_000));
+
+        // Evicted by `rotate`: only the key used within the TTL survives.
+        assert!(check_at(&mut gate, ip("10.0.0.2"), now_ms));
+        gate.rotate();
+        let policy = &gate.policies[0];
+        assert_eq!(policy.buckets.len(), 1);
+        assert_eq!(policy.tiers.len(), 1);
+    }
+
+    #[test]
+    fn dispose_frees_state_but_keeps_held_slots() {
+        let mut gate = gate(serde_json::json!([
+            { "id": "ip", "match": "ip:*", "limitPerSecond": 1, "burst": 1, "windowSeconds": 1 },
+            { "id": "inflight", "match": "ip:*", "limitPerSecond": 0, "burst": 0,
+              "windowSeconds": 0, "maxConcurrent": 1 }
+        ]));
+        let mut slot = None;
+        for host in 0..64 {
+            let result = gate.check(ip(&format!("10.0.1.{host}")));
+            slot = slot.or(result.decisions["inflight"].concurrency_key.clone());
+        }
+        let before = gate.state_bytes();
+
+        gate.dispose();
+        assert!(gate.state_bytes() < before);
+        assert!(gate.check(ip("10.0.1.1")).decisions["ip"].allowed);
+        assert!(!gate.check(ip("10.0.1.0")).decisions["inflight"].allowed);
+        assert!(gate.release_key(&slot.unwrap()));
+        assert!(gate.check(ip("10.0.1.0")).decisions["inflight"].allowed);
+    }
+
+    #[test]
+    fn held_slots_and_grant_ids_survive_reload() {
+        let policies = serde_json::json!([
+            { "id": "inflight", "match": "ip:*", "limitPerSecond": 0, "burst": 0,
+              "windowSeconds": 0, "maxConcurrent": 1 }
+        ]);
+        let mut gate = gate(policies.clone());
+        let slot = |result: CheckResult| result.decisions["inflight"].concurrency_key.clone();
+        let first = slot(gate.check(ip("10.0.0.1"))).unwrap();
+        assert!(gate.release_key(&first));
+        let held = slot(gate.check(ip("10.0.0.1"))).unwrap();
+
+        gate.reload(serde_json::from_value(serde_json::json!({ "policies": policies })).unwrap())
+            .unwrap();
+        assert!(!gate.check(ip("10.0.0.1")).allowed);
+        assert!(!gate.release_key(&first));
+        assert!(gate.release_key(&held));
+        let after = slot(gate.check(ip("10.0.0.1"))).unwrap();
+        assert!(after != first && after != held);
+    }
+
+    #[test]
+    fn denial_is_rendered_for_the_first_enforcing_policy() {
+        let mut gate = gate(serde_json::json!([
+            { "id": "shadow", "match": "ip:*", "limitPerSecond": 1, "burst": 1,
+              "windowSeconds": 1, "action": "annotate", "denyBody": "shadow" },
+            { "id": "quiet", "match": "ip:*", "limitPerSecond": 1, "burst": 1,
+              "windowSeconds": 1 },
+            { "id": "ip", "match": "ip:*", "limitPerSecond": 1, "burst": 1,
+              "windowSeconds": 1, "denyBody": "slow down, {policy_id}" }
+        ]));
+
+        let admitted = gate.check(ip("10.0.0.1"));
+        assert!(gate.render_denial(&admitted).is_none());
+        let denied = gate.check(ip("10.0.0.1"));
+        assert_eq!(denied.annotated_by, ["shadow"]);
+        // `quiet` has no body, so the next denying policy renders it.
+        let denial = gate.render_denial(&denied).unwrap();
+        assert_eq!(denial.policy_id, "ip");
+        assert_eq!(denial.body, "slow down, ip");
+        assert_eq!(denial.content_type, "text/plain");
     }
 }
@@ -6,6 +6,9 @@ pub struct Metrics {
     checks_total: u64,
     allowed_total: u64,
     denied_total: u64,
+    budget_exceeded_total: u64,
+    overloaded_total: u64,
+    decision_log_dropped_total: u64,
 }
 
 impl Metrics {
@@ -18,11 +21,32 @@ impl Metrics {
         }
     }
 
+    pub fn record_budget_exceeded(&mut self) {
+        self.budget_exceeded_total += 1;
+    }
+
+    pub fn record_overloaded(&mut self) {
+        self.overloaded_total += 1;
+    }
+
+    pub fn record_decision_log_dropped(&mut self) {
+        self.decision_log_dropped_total += 1;
+    }
+
     pub fn as_map(&self) -> IndexMap<String, u64> {
         let mut map = IndexMap::new();
         map.insert("checks_total".to_string(), self.checks_total);
         map.insert("allowed_total".to_string(), self.allowed_total);
         map.insert("denied_total".to_string(), self.denied_total);
+        map.insert(
+            "budget_exceeded_total".to_string(),
+            self.budget_exceeded_total,
+        );
+        map.insert("overloaded_total".to_string(), self.overloaded_total);
+        map.insert(
+            "decision_log_dropped_total".to_string(),
+            self.decision_log_dropped_total,
+        );
         map
     }
 }
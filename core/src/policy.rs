@@ -7,6 +7,10 @@ pub struct PolicyMatcher {
     clauses: Vec<MatchClause>,
 }
 
+/// Matching stopped because it would have done more work than allowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BudgetExhausted;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct MatchClause {
     kind: MatchKind,
@@ -15,53 +19,109 @@ struct MatchClause {
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(tag = "type", content = "value")]
 enum MatchKind {
     Ip,
     Route,
     Header,
     Attr,
+    Asn,
+    Country,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(tag = "kind", content = "data")]
 enum MatchPattern {
     Any,
     Equals(String),
     Prefix(String),
     Exists,
+    InSet(Vec<String>),
+    NotInSet(Vec<String>),
+}
+
+/// Named definitions a match rule can refer to: `$name` matcher aliases and
+/// `@{name}` value sets.
+#[derive(Default)]
+pub struct MatchScope {
+    pub aliases: IndexMap<String, PolicyMatcher>,
+    sets: IndexMap<String, Vec<String>>,
+}
+
+impl MatchScope {
+    pub fn with_sets(sets: IndexMap<String, Vec<serde_json::Value>>) -> Self {
+        let sets = sets
+            .into_iter()
+            .map(|(name, values)| (name, values.iter().map(value_to_string).collect()))
+            .collect();
+        Self {
+            aliases: IndexMap::new(),
+            sets,
+        }
+    }
+
+    /// Returns the named set normalized for `kind`, sorted for binary search.
+    fn set(&self, name: &str, kind: &MatchKind) -> Result<Vec<String>, String> {
+        let values = self
+            .sets
+            .get(name)
+            .ok_or_else(|| format!("unknown set: @{name}"))?;
+        let mut normalized: Vec<String> =
+            values.iter().map(|value| kind.normalize(value)).collect();
+        normalized.sort();
+        normalized.dedup();
+        Ok(normalized)
+    }
 }
 
 impl PolicyMatcher {
-    pub fn from_rule(rule: &str) -> Result<Self, String> {
+    /// Parses a match rule. `$name` tokens splice in the clauses of a named
+    /// matcher from `scope`, which have already been compiled.
+    pub fn from_rule(rule: &str, scope: &MatchScope) -> Result<Self, String> {
         let mut clauses = Vec::new();
         for token in rule.split_whitespace().filter(|token| !token.is_empty()) {
-            if let Some(rest) = token.strip_prefix("ip:") {
+            if let Some(name) = token.strip_prefix('$') {
+                let alias = scope
+                    .aliases
+                    .get(name)
+                    .ok_or_else(|| format!("unknown matcher alias: ${name}"))?;
+                clauses.extend(alias.clauses.iter().cloned());
+            } else if let Some(rest) = token.strip_prefix("ip:") {
                 clauses.push(MatchClause {
+                    pattern: MatchPattern::parse(rest, scope, &MatchKind::Ip)?,
                     kind: MatchKind::Ip,
-                    pattern: MatchPattern::parse(rest)?,
                     key: "ip".to_string(),
                 });
             } else if let Some(rest) = token.strip_prefix("route:") {
                 clauses.push(MatchClause {
+                    pattern: MatchPattern::parse(rest, scope, &MatchKind::Route)?,
                     kind: MatchKind::Route,
-                    pattern: MatchPattern::parse(rest)?,
                     key: "route".to_string(),
                 });
             } else if let Some(rest) = token.strip_prefix("header:") {
-                let (name, pattern) = parse_header_clause(rest)?;
+                let (name, pattern) = parse_header_clause(rest, scope)?;
                 clauses.push(MatchClause {
                     kind: MatchKind::Header,
                     pattern,
                     key: name,
                 });
             } else if let Some(rest) = token.strip_prefix("attr:") {
-                let (name, pattern) = parse_attr_clause(rest)?;
+                let (name, pattern) = parse_attr_clause(rest, scope)?;
                 clauses.push(MatchClause {
                     kind: MatchKind::Attr,
                     pattern,
                     key: name,
                 });
+            } else if let Some(rest) = token.strip_prefix("asn:") {
+                clauses.push(MatchClause {
+                    pattern: MatchPattern::parse(rest, scope, &MatchKind::Asn)?,
+                    kind: MatchKind::Asn,
+                    key: "asn".to_string(),
+                });
+            } else if let Some(rest) = token.strip_prefix("country:") {
+                clauses.push(MatchClause {
+                    pattern: MatchPattern::parse(rest, scope, &MatchKind::Country)?,
+                    kind: MatchKind::Country,
+                    key: "country".to_string(),
+                });
             } else {
                 return Err(format!("unsupported matcher token: {token}"));
             }
@@ -74,9 +134,51 @@ impl PolicyMatcher {
         Ok(Self { clauses })
     }
 
+    pub fn clause_count(&self) -> usize {
+        self.clauses.len()
+    }
+
+    /// Returns the captures `matches` would produce for a request carrying
+    /// exactly `fields`, which are named like clause tokens (`ip`, `route`,
+    /// `header:x-api-key`, `attr:plan`, `asn`, `country`). Every clause must be
+    /// covered and no other field may be given.
+    pub fn captures_for(
+        &self,
+        fields: &IndexMap<String, String>,
+    ) -> Result<IndexMap<String, String>, String> {
+        let mut captured = IndexMap::new();
+        for clause in &self.clauses {
+            let field = clause.field();
+            let value = fields
+                .get(&field)
+                .ok_or_else(|| format!("key does not set {field}"))?;
+            captured.insert(clause.key.clone(), clause.kind.normalize(value));
+        }
+        if let Some(extra) = fields
+            .keys()
+            .find(|field| !self.clauses.iter().any(|clause| clause.field() == **field))
+        {
+            return Err(format!("key field {extra} is not in the match rule"));
+        }
+        Ok(captured)
+    }
+
     pub fn matches(&self, request: &CheckRequest) -> Option<IndexMap<String, String>> {
+        let mut unmetered = u32::MAX;
+        self.matches_within(request, &mut unmetered).ok().flatten()
+    }
+
+    /// Like `matches`, but charges `work` one unit per clause evaluated (a
+    /// mismatch stops evaluation) and one per nested attr path segment
+    /// walked. Fails without matching further once `work` runs out.
+    pub fn matches_within(
+        &self,
+        request: &CheckRequest,
+        work: &mut u32,
+    ) -> Result<Option<IndexMap<String, String>>, BudgetExhausted> {
         let mut captured = IndexMap::new();
         for clause in &self.clauses {
+            let mut walked = 0;
             let source_value = match clause.kind {
                 MatchKind::Ip => request.ip.clone(),
                 MatchKind::Route => request.route.clone(),
@@ -89,20 +191,60 @@ impl PolicyMatcher {
                 MatchKind::Attr => request
                     .attrs
                     .as_ref()
-                    .and_then(|attrs| attrs.get(&clause.key))
+                    .and_then(|attrs| walk_attr(attrs, &clause.key, &mut walked))
                     .map(value_to_string),
+                MatchKind::Asn | MatchKind::Country => request
+                    .attrs
+                    .as_ref()
+                    .and_then(|attrs| attrs.get(&clause.key))
+                    .map(|value| clause.kind.normalize(&value_to_string(value))),
             };
 
-            let capture = match_value(&clause.pattern, source_value)?;
+            *work = work.checked_sub(1 + walked).ok_or(BudgetExhausted)?;
+            let Some(capture) = match_value(&clause.pattern, source_value) else {
+                return Ok(None);
+            };
             captured.insert(clause.key.clone(), capture);
         }
 
-        Some(captured)
+        Ok(Some(captured))
+    }
+}
+
+impl MatchClause {
+    /// The clause's source written as in a match rule, without the pattern.
+    fn field(&self) -> String {
+        match self.kind {
+            MatchKind::Ip => "ip".to_string(),
+            MatchKind::Route => "route".to_string(),
+            MatchKind::Header => format!("header:{}", self.key),
+            MatchKind::Attr => format!("attr:{}", self.key),
+            MatchKind::Asn => "asn".to_string(),
+            MatchKind::Country => "country".to_string(),
+        }
+    }
+}
+
+impl MatchKind {
+    /// Canonical form used on both sides of a comparison: ASNs drop an `AS`
+    /// prefix and country codes are upper-cased.
+    fn normalize(&self, value: &str) -> String {
+        match self {
+            MatchKind::Asn => {
+                let value = value.trim();
+                match value.get(..2) {
+                    Some(prefix) if prefix.eq_ignore_ascii_case("as") => value[2..].to_string(),
+                    _ => value.to_string(),
+                }
+            }
+            MatchKind::Country => value.trim().to_ascii_uppercase(),
+            _ => value.to_string(),
+        }
     }
 }
 
 impl MatchPattern {
-    fn parse(input: &str) -> Result<Self, String> {
+    fn parse(input: &str, scope: &MatchScope, kind: &MatchKind) -> Result<Self, String> {
         if input.is_empty() || input == "*" {
             return Ok(MatchPattern::Any);
         }
@@ -111,18 +253,32 @@ impl MatchPattern {
             return Ok(MatchPattern::Exists);
         }
 
+        // Set references are braced so that plain values starting with `@`
+        // (`header:x-team=@ops`) keep matching literally.
+        if let Some(name) = set_reference(input, "!@{") {
+            return Ok(MatchPattern::NotInSet(scope.set(name, kind)?));
+        }
+
+        if let Some(name) = set_reference(input, "@{") {
+            return Ok(MatchPattern::InSet(scope.set(name, kind)?));
+        }
+
         if let Some(prefix) = input.strip_suffix('*') {
             if prefix.is_empty() {
                 return Ok(MatchPattern::Any);
             }
-            return Ok(MatchPattern::Prefix(prefix.to_string()));
+            return Ok(MatchPattern::Prefix(kind.normalize(prefix)));
         }
 
-        Ok(MatchPattern::Equals(input.to_string()))
+        Ok(MatchPattern::Equals(kind.normalize(input)))
     }
 }
 
-fn parse_header_clause(input: &str) -> Result<(String, MatchPattern), String> {
+fn set_reference<'a>(input: &'a str, opener: &str) -> Option<&'a str> {
+    input.strip_prefix(opener)?.strip_suffix('}')
+}
+
+fn parse_header_clause(input: &str, scope: &MatchScope) -> Result<(String, MatchPattern), String> {
     let mut parts = input.splitn(2, '=');
     let name = parts
         .next()
@@ -130,22 +286,60 @@ fn parse_header_clause(input: &str) -> Result<(String, MatchPattern), String> {
         .to_string();
     let name = name.trim().to_string();
     let value = parts.next().unwrap_or("*");
-    let pattern = MatchPattern::parse(value.trim())?;
+    let pattern = MatchPattern::parse(value.trim(), scope, &MatchKind::Header)?;
     Ok((name, pattern))
 }
 
-fn parse_attr_clause(input: &str) -> Result<(String, MatchPattern), String> {
+fn parse_attr_clause(input: &str, scope: &MatchScope) -> Result<(String, MatchPattern), String> {
     let mut parts = input.splitn(2, '=');
     let name = parts
         .next()
         .ok_or_else(|| "attr clause missing name".to_string())?
         .to_string();
     let value = parts.next().unwrap_or("*");
-    let pattern = MatchPattern::parse(value.trim())?;
+    let pattern = MatchPattern::parse(value.trim(), scope, &MatchKind::Attr)?;
     Ok((name, pattern))
 }
 
-fn value_to_string(value: &serde_json::Value) -> String {
+/// Looks up `path` in `attrs`. A top-level attr with exactly that name wins;
+/// otherwise the path is walked through nested objects by `.`-separated
+/// names, with array elements addressed as `items[0]` or `items.0`.
+pub fn attr_value<'a>(
+    attrs: &'a IndexMap<String, serde_json::Value>,
+    path: &str,
+) -> Option<&'a serde_json::Value> {
+    walk_attr(attrs, path, &mut 0)
+}
+
+/// `attr_value`, counting in `walked` the path segments looked up after the
+/// flat key missed.
+fn walk_attr<'a>(
+    attrs: &'a IndexMap<String, serde_json::Value>,
+    path: &str,
+    walked: &mut u32,
+) -> Option<&'a serde_json::Value> {
+    if let Some(value) = attrs.get(path) {
+        return Some(value);
+    }
+    if !path.contains(['.', '[']) {
+        return None;
+    }
+    let mut segments = path.split(['.', '[']);
+    *walked += 1;
+    let mut value = attrs.get(segments.next()?)?;
+    for segment in segments {
+        *walked += 1;
+        let segment = segment.strip_suffix(']').unwrap_or(segment);
+        value = match value {
+            serde_json::Value::Object(map) => map.get(segment)?,
+            serde_json::Value::Array(items) => items.get(segment.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+pub fn value_to_string(value: &serde_json::Value) -> String {
     match value {
         serde_json::Value::Null => "null".to_string(),
         serde_json::Value::Bool(b) => b.to_string(),
@@ -162,5 +356,84 @@ fn match_value(pattern: &MatchPattern, value: Option<String>) -> Option<String>
         MatchPattern::Exists => value,
         MatchPattern::Equals(expected) => value.filter(|val| val == expected),
         MatchPattern::Prefix(prefix) => value.filter(|val| val.starts_with(prefix)),
+        MatchPattern::InSet(set) => value.filter(|val| set.binary_search(val).is_ok()),
+        MatchPattern::NotInSet(set) => value.filter(|val| set.binary_search(val).is_err()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MatchScope, PolicyMatcher};
+    use crate::config::CheckRequest;
+    use indexmap::IndexMap;
+    use serde_json::json;
+
+    fn request(attrs: serde_json::Value) -> CheckRequest {
+        CheckRequest {
+            attrs: serde_json::from_value(attrs).unwrap(),
+            ..CheckRequest::default()
+        }
+    }
+
+    #[test]
+    fn asn_and_country_sets_normalize_values() {
+        let mut sets = IndexMap::new();
+        sets.insert("hosting".to_string(), vec![json!(16509), json!("AS14061")]);
+        sets.insert("home".to_string(), vec![json!("us"), json!("CA")]);
+        let scope = MatchScope::with_sets(sets);
+
+        let asn = PolicyMatcher::from_rule("asn:@{hosting}", &scope).unwrap();
+        assert!(asn.matches(&request(json!({"asn": "as16509"}))).is_some());
+        assert!(asn.matches(&request(json!({"asn": 14061}))).is_some());
+        assert!(asn.matches(&request(json!({"asn": 3320}))).is_none());
+
+        let abroad = PolicyMatcher::from_rule("country:!@{home}", &scope).unwrap();
+        assert!(abroad.matches(&request(json!({"country": "de"}))).is_some());
+        assert!(abroad.matches(&request(json!({"country": "Us"}))).is_none());
+        assert!(abroad.matches(&request(json!({}))).is_none());
+    }
+
+    #[test]
+    fn values_starting_with_at_sign_match_literally() {
+        let mut sets = IndexMap::new();
+        sets.insert("ops".to_string(), vec![json!("sre")]);
+        let scope = MatchScope::with_sets(sets);
+        let team = |value: &str| CheckRequest {
+            headers: Some([("x-team".to_string(), Some(value.to_string()))].into()),
+            ..CheckRequest::default()
+        };
+
+        let literal = PolicyMatcher::from_rule("header:x-team=@ops", &scope).unwrap();
+        assert!(literal.matches(&team("@ops")).is_some());
+        assert!(literal.matches(&team("sre")).is_none());
+        let negated = PolicyMatcher::from_rule("header:x-team=!@ops", &scope).unwrap();
+        assert!(negated.matches(&team("!@ops")).is_some());
+
+        let in_set = PolicyMatcher::from_rule("header:x-team=@{ops}", &scope).unwrap();
+        assert!(in_set.matches(&team("sre")).is_some());
+        assert!(in_set.matches(&team("@ops")).is_none());
+        assert!(PolicyMatcher::from_rule("header:x-team=@{missing}", &scope).is_err());
+    }
+
+    #[test]
+    fn attr_paths_reach_into_nested_objects_and_arrays() {
+        let scope = MatchScope::default();
+        let attrs = json!({
+            "user": { "org": { "id": 42 }, "roles": ["admin", "billing"] },
+            "flat.name": "kept"
+        });
+
+        let org = PolicyMatcher::from_rule("attr:user.org.id=?", &scope).unwrap();
+        let captured = org.matches(&request(attrs.clone())).unwrap();
+        assert_eq!(captured["user.org.id"], "42");
+        assert!(org.matches(&request(json!({ "user": {} }))).is_none());
+
+        let role = PolicyMatcher::from_rule("attr:user.roles[1]=billing", &scope).unwrap();
+        assert!(role.matches(&request(attrs.clone())).is_some());
+        let dotted = PolicyMatcher::from_rule("attr:user.roles.0=admin", &scope).unwrap();
+        assert!(dotted.matches(&request(attrs.clone())).is_some());
+
+        let flat = PolicyMatcher::from_rule("attr:flat.name=kept", &scope).unwrap();
+        assert!(flat.matches(&request(attrs)).is_some());
     }
 }
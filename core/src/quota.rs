@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+
+const MS_PER_HOUR: u64 = 3_600_000;
+const MS_PER_DAY: u64 = 86_400_000;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Quota {
+    pub limit: u64,
+    pub period: QuotaPeriod,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum QuotaPeriod {
+    Hour,
+    Day,
+    Month,
+}
+
+/// Usage within the current calendar-aligned (UTC) quota period.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QuotaWindow {
+    used: f64,
+    reset_ms: u64,
+}
+
+impl QuotaPeriod {
+    /// Epoch milliseconds at which the period containing `now_ms` ends.
+    pub fn next_reset(self, now_ms: u64) -> u64 {
+        match self {
+            QuotaPeriod::Hour => (now_ms / MS_PER_HOUR + 1) * MS_PER_HOUR,
+            QuotaPeriod::Day => (now_ms / MS_PER_DAY + 1) * MS_PER_DAY,
+            QuotaPeriod::Month => {
+                let (year, month) = civil_from_days((now_ms / MS_PER_DAY) as i64);
+                let (year, month) = if month == 12 {
+                    (year + 1, 1)
+                } else {
+                    (year, month + 1)
+                };
+                days_from_civil(year, month) as u64 * MS_PER_DAY
+            }
+        }
+    }
+}
+
+impl QuotaWindow {
+    pub fn new(period: QuotaPeriod, now_ms: u64) -> Self {
+        Self {
+            used: 0.0,
+            reset_ms: period.next_reset(now_ms),
+        }
+    }
+
+    /// Starts a fresh period once the current one has ended.
+    pub fn roll(&mut self, period: QuotaPeriod, now_ms: u64) {
+        if self.expired(now_ms) {
+            *self = Self::new(period, now_ms);
+        }
+    }
+
+    pub fn remaining(&self, quota: &Quota) -> f64 {
+        (quota.limit as f64 - self.used).max(0.0)
+    }
+
+    pub fn record(&mut self, cost: f64) {
+        self.used += cost;
+    }
+
+    pub fn refund(&mut self, cost: f64) {
+        self.used = (self.used - cost).max(0.0);
+    }
+
+    pub fn reset_ms(&self) -> u64 {
+        self.reset_ms
+    }
+
+    pub fn expired(&self, now_ms: u64) -> bool {
+        now_ms >= self.reset_ms
+    }
+}
+
+/// Converts days since the Unix epoch to a (year, month) pair in the
+/// proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month as u32)
+}
+
+/// Days since the Unix epoch of the first day of `month` in `year`.
+fn days_from_civil(year: i64, month: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = i64::from(month);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QuotaPeriod;
+
+    #[test]
+    fn periods_reset_on_utc_calendar_boundaries() {
+        // 2024-02-15T10:30:00Z
+        let now_ms = 1_707_993_000_000;
+        assert_eq!(QuotaPeriod::Hour.next_reset(now_ms), 1_707_994_800_000);
+        assert_eq!(QuotaPeriod::Day.next_reset(now_ms), 1_708_041_600_000);
+        // 2024-03-01T00:00:00Z
+        assert_eq!(QuotaPeriod::Month.next_reset(now_ms), 1_709_251_200_000);
+        // 2023-12-31T23:59:59Z rolls into 2024-01-01T00:00:00Z
+        assert_eq!(
+            QuotaPeriod::Month.next_reset(1_704_067_199_000),
+            1_704_067_200_000
+        );
+    }
+}
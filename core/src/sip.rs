@@ -0,0 +1,243 @@
+//! SipHash-1-3 over pre-serialized key material, used to hash whole batches of
+//! bucket keys at once. With the `simd128` feature on a wasm32 target built with
+//! `+simd128`, messages are hashed two at a time in the lanes of a `v128`;
+//! everywhere else the scalar path is used. Both produce exactly the same
+//! values as `SipHasher13`.
+
+#[derive(Clone, Copy)]
+struct SipState {
+    v0: u64,
+    v1: u64,
+    v2: u64,
+    v3: u64,
+}
+
+impl SipState {
+    fn new(k0: u64, k1: u64) -> Self {
+        Self {
+            v0: k0 ^ 0x736f_6d65_7073_6575,
+            v1: k1 ^ 0x646f_7261_6e64_6f6d,
+            v2: k0 ^ 0x6c79_6765_6e65_7261,
+            v3: k1 ^ 0x7465_6462_7974_6573,
+        }
+    }
+
+    fn round(&mut self) {
+        self.v0 = self.v0.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(13);
+        self.v1 ^= self.v0;
+        self.v0 = self.v0.rotate_left(32);
+        self.v2 = self.v2.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(16);
+        self.v3 ^= self.v2;
+        self.v0 = self.v0.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(21);
+        self.v3 ^= self.v0;
+        self.v2 = self.v2.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(17);
+        self.v1 ^= self.v2;
+        self.v2 = self.v2.rotate_left(32);
+    }
+
+    fn absorb(&mut self, word: u64) {
+        self.v3 ^= word;
+        self.round();
+        self.v0 ^= word;
+    }
+
+    fn finish(mut self, tail: &[u8], length: usize) -> u64 {
+        let mut last = [0u8; 8];
+        last[..tail.len()].copy_from_slice(tail);
+        self.absorb(((length as u64 & 0xff) << 56) | u64::from_le_bytes(last));
+        self.v2 ^= 0xff;
+        self.round();
+        self.round();
+        self.round();
+        self.v0 ^ self.v1 ^ self.v2 ^ self.v3
+    }
+}
+
+fn word_at(message: &[u8], index: usize) -> u64 {
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&message[index * 8..index * 8 + 8]);
+    u64::from_le_bytes(word)
+}
+
+/// Continues hashing `message` from `state`, which has already absorbed the
+/// first `start_word` 8-byte words.
+fn hash_from(mut state: SipState, message: &[u8], start_word: usize) -> u64 {
+    let words = message.len() / 8;
+    for index in start_word..words {
+        state.absorb(word_at(message, index));
+    }
+    state.finish(&message[words * 8..], message.len())
+}
+
+#[cfg(all(
+    feature = "simd128",
+    target_arch = "wasm32",
+    target_feature = "simd128"
+))]
+pub fn hash_batch(k0: u64, k1: u64, messages: &[Vec<u8>]) -> Vec<u64> {
+    lanes::hash_pairs(k0, k1, messages)
+}
+
+#[cfg(not(all(
+    feature = "simd128",
+    target_arch = "wasm32",
+    target_feature = "simd128"
+)))]
+pub fn hash_batch(k0: u64, k1: u64, messages: &[Vec<u8>]) -> Vec<u64> {
+    messages
+        .iter()
+        .map(|message| hash_from(SipState::new(k0, k1), message, 0))
+        .collect()
+}
+
+#[cfg(all(
+    feature = "simd128",
+    target_arch = "wasm32",
+    target_feature = "simd128"
+))]
+mod lanes {
+    use super::{hash_from, word_at, SipState};
+    use core::arch::wasm32::{
+        i64x2_add, i64x2_shl, u64x2, u64x2_extract_lane, u64x2_shr, u64x2_splat, v128, v128_or,
+        v128_xor,
+    };
+
+    struct PairState {
+        v0: v128,
+        v1: v128,
+        v2: v128,
+        v3: v128,
+    }
+
+    fn rotl(value: v128, bits: u32) -> v128 {
+        v128_or(i64x2_shl(value, bits), u64x2_shr(value, 64 - bits))
+    }
+
+    impl PairState {
+        fn new(k0: u64, k1: u64) -> Self {
+            let state = SipState::new(k0, k1);
+            Self {
+                v0: u64x2_splat(state.v0),
+                v1: u64x2_splat(state.v1),
+                v2: u64x2_splat(state.v2),
+                v3: u64x2_splat(state.v3),
+            }
+        }
+
+        fn round(&mut self) {
+            self.v0 = i64x2_add(self.v0, self.v1);
+            self.v1 = rotl(self.v1, 13);
+            self.v1 = v128_xor(self.v1, self.v0);
+            self.v0 = rotl(self.v0, 32);
+            self.v2 = i64x2_add(self.v2, self.v3);
+            self.v3 = rotl(self.v3, 16);
+            self.v3 = v128_xor(self.v3, self.v2);
+            self.v0 = i64x2_add(self.v0, self.v3);
+            self.v3 = rotl(self.v3, 21);
+            self.v3 = v128_xor(self.v3, self.v0);
+            self.v2 = i64x2_add(self.v2, self.v1);
+            self.v1 = rotl(self.v1, 17);
+            self.v1 = v128_xor(self.v1, self.v2);
+            self.v2 = rotl(self.v2, 32);
+        }
+
+        fn absorb(&mut self, words: v128) {
+            self.v3 = v128_xor(self.v3, words);
+            self.round();
+            self.v0 = v128_xor(self.v0, words);
+        }
+
+        fn split(&self) -> (SipState, SipState) {
+            let left = SipState {
+                v0: u64x2_extract_lane::<0>(self.v0),
+                v1: u64x2_extract_lane::<0>(self.v1),
+                v2: u64x2_extract_lane::<0>(self.v2),
+                v3: u64x2_extract_lane::<0>(self.v3),
+            };
+            let right = SipState {
+                v0: u64x2_extract_lane::<1>(self.v0),
+                v1: u64x2_extract_lane::<1>(self.v1),
+                v2: u64x2_extract_lane::<1>(self.v2),
+                v3: u64x2_extract_lane::<1>(self.v3),
+            };
+            (left, right)
+        }
+    }
+
+    /// Hashes messages pairwise: the words both messages share are absorbed in
+    /// lockstep, then each lane is finished on its own.
+    pub(super) fn hash_pairs(k0: u64, k1: u64, messages: &[Vec<u8>]) -> Vec<u64> {
+        let mut hashes = Vec::with_capacity(messages.len());
+        let mut pairs = messages.chunks_exact(2);
+        for pair in &mut pairs {
+            let (a, b) = (&pair[0], &pair[1]);
+            let shared = a.len().min(b.len()) / 8;
+            let mut state = PairState::new(k0, k1);
+            for index in 0..shared {
+                state.absorb(u64x2(word_at(a, index), word_at(b, index)));
+            }
+            let (left, right) = state.split();
+            hashes.push(hash_from(left, a, shared));
+            hashes.push(hash_from(right, b, shared));
+        }
+        for message in pairs.remainder() {
+            hashes.push(hash_from(SipState::new(k0, k1), message, 0));
+        }
+        hashes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hash_batch;
+    use siphasher::sip::SipHasher13;
+    use std::hash::Hasher;
+
+    const K0: u64 = 0x0706_0504_0302_0100;
+    const K1: u64 = 0x0f0e_0d0c_0b0a_0908;
+
+    /// Lengths on both sides of the word boundaries, paired short with long
+    /// and in an odd count, so the lane path absorbs uneven pairs and also
+    /// finishes a lone trailing message.
+    fn messages() -> Vec<Vec<u8>> {
+        (0..41u8)
+            .map(|idx| if idx % 2 == 0 { idx } else { 40 - idx })
+            .map(|len| (0..len).map(|byte| byte.wrapping_mul(31)).collect())
+            .collect()
+    }
+
+    fn reference(message: &[u8]) -> u64 {
+        let mut hasher = SipHasher13::new_with_keys(K0, K1);
+        hasher.write(message);
+        hasher.finish()
+    }
+
+    // Runs under wasm-bindgen-test as well, so whichever path `hash_batch`
+    // takes on that target is checked against the reference hasher.
+    #[cfg_attr(not(target_arch = "wasm32"), test)]
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen_test::wasm_bindgen_test)]
+    fn batch_matches_reference_hasher() {
+        let messages = messages();
+        let expected: Vec<u64> = messages.iter().map(|message| reference(message)).collect();
+        assert_eq!(hash_batch(K0, K1, &messages), expected);
+    }
+
+    #[cfg(all(
+        feature = "simd128",
+        target_arch = "wasm32",
+        target_feature = "simd128"
+    ))]
+    #[wasm_bindgen_test::wasm_bindgen_test]
+    fn lane_pairs_match_scalar_hashes() {
+        let messages = messages();
+        let scalar: Vec<u64> = messages
+            .iter()
+            .map(|message| super::hash_from(super::SipState::new(K0, K1), message, 0))
+            .collect();
+        assert_eq!(super::lanes::hash_pairs(K0, K1, &messages), scalar);
+    }
+}
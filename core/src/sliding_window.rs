@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+
+/// Sliding-window counter: approximates a rolling window by weighting the
+/// previous fixed window's count by how much of it still overlaps `now`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SlidingWindow {
+    window_start_ms: u64,
+    current: f64,
+    previous: f64,
+    last_ms: u64,
+}
+
+impl SlidingWindow {
+    pub fn new(window_ms: u64, now_ms: u64) -> Self {
+        Self {
+            window_start_ms: now_ms - now_ms % window_ms.max(1),
+            current: 0.0,
+            previous: 0.0,
+            last_ms: now_ms,
+        }
+    }
+
+    /// `reserve` requests of the window's `limit` are held back.
+    pub fn consume(
+        &mut self,
+        limit: u32,
+        window_ms: u64,
+        cost: f64,
+        reserve: f64,
+        now_ms: u64,
+    ) -> (bool, Option<u32>) {
+        self.last_ms = now_ms;
+        if limit == 0 {
+            return (false, None);
+        }
+
+        let window_ms = window_ms.max(1);
+        self.advance(window_ms, now_ms);
+
+        let window = window_ms as f64;
+        let into_window = now_ms.saturating_sub(self.window_start_ms) as f64;
+        let weight = 1.0 - into_window / window;
+        let limit = f64::from(limit) - reserve;
+        let estimated = self.previous * weight + self.current;
+
+        if estimated + cost <= limit {
+            self.current += cost;
+            return (true, None);
+        }
+
+        if cost > limit {
+            return (false, None);
+        }
+
+        // Find the first instant at which a request of this cost would fit.
+        let wait_ms = if self.current + cost <= limit {
+            let room = limit - self.current - cost;
+            let needed = window * (1.0 - room / self.previous);
+            needed - into_window
+        } else {
+            let room = limit - cost;
+            let needed = window * (1.0 - room / self.current);
+            window - into_window + needed
+        };
+        (false, Some(wait_ms.ceil().max(1.0) as u32))
+    }
+
+    pub fn idle_for(&self, now_ms: u64) -> u64 {
+        now_ms.saturating_sub(self.last_ms)
+    }
+
+    /// Takes `cost` back off the current window's count. A request admitted in
+    /// the previous window is credited against the current one instead.
+    pub fn refund(&mut self, window_ms: u64, cost: f64, now_ms: u64) {
+        self.advance(window_ms.max(1), now_ms);
+        self.current = (self.current - cost).max(0.0);
+    }
+
+    fn advance(&mut self, window_ms: u64, now_ms: u64) {
+        let elapsed = now_ms.saturating_sub(self.window_start_ms) / window_ms;
+        if elapsed == 0 {
+            return;
+        }
+        self.previous = if elapsed == 1 { self.current } else { 0.0 };
+        self.current = 0.0;
+        self.window_start_ms += elapsed * window_ms;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SlidingWindow;
+
+    #[test]
+    fn previous_window_weight_decays() {
+        let mut window = SlidingWindow::new(1_000, 0);
+        for _ in 0..4 {
+            assert!(window.consume(4, 1_000, 1.0, 0.0, 100).0);
+        }
+        assert_eq!(window.consume(4, 1_000, 1.0, 0.0, 999), (false, Some(251)));
+
+        // A quarter into the next window 3 of the 4 previous hits still count.
+        assert!(window.consume(4, 1_000, 1.0, 0.0, 1_250).0);
+        assert_eq!(
+            window.consume(4, 1_000, 1.0, 0.0, 1_250),
+            (false, Some(250))
+        );
+        assert!(window.consume(4, 1_000, 1.0, 0.0, 1_500).0);
+    }
+}
@@ -0,0 +1,113 @@
+//! Snapshot wire format: the `MAGIC` bytes, the format version as a
+//! little-endian `u16`, then the limiter state encoded by bincode with
+//! fixed-width little-endian integers. Struct fields are written in declaration
+//! order, so adding, removing or reordering a serialized field changes the
+//! format and must bump `FORMAT_VERSION`. Hash maps are written sorted by key,
+//! which makes the bytes a pure function of the state on every platform.
+
+use crate::error::{FluxgateError, Result};
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::{Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+
+const MAGIC: &[u8; 4] = b"FGSN";
+pub const FORMAT_VERSION: u16 = 1;
+
+fn codec() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+}
+
+pub fn encode<T: Serialize>(state: &T) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    codec()
+        .serialize_into(&mut bytes, state)
+        .map_err(|err| FluxgateError::Serialization(err.to_string()))?;
+    Ok(bytes)
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    let body = bytes
+        .strip_prefix(MAGIC.as_slice())
+        .ok_or_else(|| FluxgateError::Serialization("not a fluxgate snapshot".to_string()))?;
+    let (version, state) = match body {
+        [lo, hi, state @ ..] => (u16::from_le_bytes([*lo, *hi]), state),
+        _ => {
+            return Err(FluxgateError::Serialization(
+                "truncated snapshot header".to_string(),
+            ))
+        }
+    };
+    if version != FORMAT_VERSION {
+        return Err(FluxgateError::Serialization(format!(
+            "unsupported snapshot format version {version} (expected {FORMAT_VERSION})"
+        )));
+    }
+    codec()
+        .deserialize(state)
+        .map_err(|err| FluxgateError::Serialization(err.to_string()))
+}
+
+/// `serialize_with` helper that writes a per-key map in ascending key order.
+pub fn sorted<S, V>(map: &HashMap<u64, V>, serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    V: Serialize,
+{
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, FORMAT_VERSION};
+    use serde::Serialize;
+    use std::collections::HashMap;
+
+    #[derive(Serialize)]
+    struct Counts {
+        #[serde(serialize_with = "super::sorted")]
+        counts: HashMap<u64, u32>,
+    }
+
+    // Runs under wasm-bindgen-test as well, so both targets are pinned to the
+    // same bytes.
+    #[cfg_attr(not(target_arch = "wasm32"), test)]
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen_test::wasm_bindgen_test)]
+    fn encoding_is_fixed_width_little_endian_and_sorted() {
+        let counts = Counts {
+            counts: HashMap::from([(2, 7), (1, 0x0102_0304)]),
+        };
+        let bytes = encode(&counts).unwrap();
+        let [version_lo, version_hi] = FORMAT_VERSION.to_le_bytes();
+        #[rustfmt::skip]
+        let expected = [
+            b'F', b'G', b'S', b'N', version_lo, version_hi,
+            2, 0, 0, 0, 0, 0, 0, 0,
+            1, 0, 0, 0, 0, 0, 0, 0, 4, 3, 2, 1,
+            2, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0,
+        ];
+        assert_eq!(bytes, expected);
+
+        let decoded: HashMap<u64, u32> = decode(&bytes).unwrap();
+        assert_eq!(decoded, counts.counts);
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let bytes = encode(&HashMap::from([(1u64, 1u32)])).unwrap();
+        for version in [0, FORMAT_VERSION + 1, u16::MAX] {
+            let mut other = bytes.clone();
+            other[4..6].copy_from_slice(&version.to_le_bytes());
+            let err = decode::<HashMap<u64, u32>>(&other).unwrap_err();
+            assert!(err
+                .to_string()
+                .contains("unsupported snapshot format version"));
+        }
+        assert!(decode::<HashMap<u64, u32>>(&bytes[..5]).is_err());
+        assert!(decode::<HashMap<u64, u32>>(b"XXXX\x01\x00").is_err());
+    }
+}
@@ -1,9 +1,14 @@
 import initWasm, * as wasm from '../pkg/fluxgate_wasm.js';
 import type {
+  BannedKey,
   Fluxgate,
   FluxgateInit,
   CheckRequest,
+  CheckOptions,
   CheckResult,
+  CheckRequestSource,
+  DenialResponse,
+  LimitOverride,
 } from './types.js';
 
 let wasmReady: Promise<unknown> | null = null;
@@ -19,6 +24,20 @@ function parseResult(result: string): CheckResult {
   return JSON.parse(result) as CheckResult;
 }
 
+function pullRequests(source: CheckRequestSource): () => string | undefined {
+  if (typeof source === 'function') {
+    return () => {
+      const req = source();
+      return req == null ? undefined : JSON.stringify(req);
+    };
+  }
+  const iterator = source[Symbol.iterator]();
+  return () => {
+    const next = iterator.next();
+    return next.done ? undefined : JSON.stringify(next.value);
+  };
+}
+
 export async function createFluxgate(init: FluxgateInit): Promise<Fluxgate> {
   await ensureWasmLoaded();
   const ctor = (wasm as any).WasmFluxgate;
@@ -28,17 +47,56 @@ export async function createFluxgate(init: FluxgateInit): Promise<Fluxgate> {
   const instance = new ctor(JSON.stringify(init));
 
   return {
-    check(req: CheckRequest): CheckResult {
-      const response = instance.check(JSON.stringify(req));
+    check(req: CheckRequest, options?: CheckOptions): CheckResult {
+      const response = instance.check(
+        JSON.stringify(req),
+        options === undefined ? undefined : JSON.stringify(options),
+      );
       return parseResult(response);
     },
+    peek(req: CheckRequest): CheckResult {
+      return parseResult(instance.peek(JSON.stringify(req)));
+    },
+    checkN(req: CheckRequest, n: number): CheckResult {
+      return parseResult(instance.check_n(JSON.stringify(req), n));
+    },
     checkBatch(reqs: CheckRequest[]): CheckResult[] {
       const response = instance.check_batch(JSON.stringify(reqs));
       return JSON.parse(response) as CheckResult[];
     },
+    checkStream(source: CheckRequestSource, onResult: (result: CheckResult) => void): number {
+      return instance.check_stream(pullRequests(source), (response: string) =>
+        onResult(parseResult(response)),
+      );
+    },
+    renderDenial(result: CheckResult): DenialResponse | undefined {
+      const response = instance.render_denial(JSON.stringify(result));
+      return response === undefined ? undefined : (JSON.parse(response) as DenialResponse);
+    },
+    release(req: CheckRequest): number {
+      return instance.release(JSON.stringify(req));
+    },
+    releaseKey(key: string): boolean {
+      return instance.release_key(key);
+    },
+    refund(req: CheckRequest): number {
+      return instance.refund(JSON.stringify(req));
+    },
+    drainDecisionLog(): string {
+      return instance.drain_decision_log();
+    },
+    bannedKeys(): BannedKey[] {
+      return JSON.parse(instance.banned_keys()) as BannedKey[];
+    },
     rotate(): void {
       instance.rotate();
     },
+    setOverrides(overrides: Record<string, LimitOverride[]>): void {
+      instance.set_overrides(JSON.stringify(overrides));
+    },
+    setPressure(factor: number): void {
+      instance.set_pressure(factor);
+    },
     reload(cfg: FluxgateInit): void {
       instance.reload(JSON.stringify(cfg));
     },
@@ -49,6 +107,12 @@ export async function createFluxgate(init: FluxgateInit): Promise<Fluxgate> {
     restore(bytes: Uint8Array): void {
       instance.restore(bytes);
     },
+    dispose(): void {
+      instance.dispose();
+    },
+    stateBytes(): number {
+      return instance.state_bytes();
+    },
     metrics(): Record<string, number> {
       const response = instance.metrics();
       return JSON.parse(response) as Record<string, number>;
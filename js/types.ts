@@ -1,6 +1,20 @@
 export type FluxgateInit = {
   policies?: FluxgatePolicy[];
   configText?: string;
+  configTexts?: string[];
+  sets?: Record<string, Array<string | number>>;
+  matchers?: Record<string, string>;
+  enrich?: EnrichStep[];
+  environments?: Record<string, { scale: number }>;
+  environment?: string;
+  decisionLog?: boolean;
+  decisionLogMaxLines?: number;
+  evaluationBudget?: {
+    maxPolicies?: number;
+    maxClauses?: number;
+    onExceeded?: 'open' | 'closed';
+  };
+  maxChecksPerSecond?: number;
   keySecret?: string;
   slices?: number;
   sketchWidth?: number;
@@ -16,29 +30,122 @@ export type FluxgatePolicy = {
   limitPerSecond: number;
   burst: number;
   windowSeconds: number;
+  parent?: string;
   action?: 'reject' | 'annotate';
+  algorithm?: 'token_bucket' | 'gcra' | 'sliding_window' | 'leaky_bucket';
+  quota?: { limit: number; period: 'hour' | 'day' | 'month' };
+  tiers?: Array<{ limit: number; windowSeconds: number }>;
+  priorityReserve?: { normal?: number; low?: number };
+  maxConcurrent?: number;
+  leaseSeconds?: number;
+  bucketTtlSeconds?: number;
+  transitionSeconds?: number;
+  overrides?: LimitOverride[];
+  penalty?: { thresholdDenials: number; banSeconds: number; windowSeconds?: number };
+  denyBody?: string;
+  denyContentType?: string;
 };
 
+export type LimitOverride = {
+  key: Record<string, string | number>;
+  limitPerSecond?: number;
+  burst?: number;
+};
+
+export type EnrichStep = {
+  from: string;
+  to: string;
+  transform: 'copy' | 'lowercase' | 'prefix' | 'split' | 'hash' | 'bucket';
+  length?: number;
+  separator?: string;
+  index?: number;
+  buckets?: Record<string, string[]>;
+  default?: string;
+};
+
+export type AttrValue =
+  | string
+  | number
+  | boolean
+  | null
+  | undefined
+  | AttrValue[]
+  | { [key: string]: AttrValue };
+
 export type CheckRequest = {
   ip?: string;
   route?: string;
   headers?: Record<string, string | undefined>;
-  attrs?: Record<string, string | number | boolean | null | undefined>;
+  attrs?: Record<string, AttrValue>;
+  requestId?: string;
+  cost?: number;
+  priority?: 'high' | 'normal' | 'low';
+};
+
+export type CheckDecision = {
+  allowed: boolean;
+  retryAfterMs?: number;
+  quotaRemaining?: number;
+  quotaResetMs?: number;
+  concurrencyKey?: string;
+  delayMs?: number;
+  banned?: boolean;
+};
+
+export type BannedKey = {
+  policyId: string;
+  key: string;
+  bannedUntilMs: number;
+};
+
+export type CheckOptions = {
+  dryRun?: boolean;
+  skipPolicies?: string[];
 };
 
 export type CheckResult = {
   allowed: boolean;
   retryAfterMs?: number;
-  decisions: Record<string, { allowed: boolean; retryAfterMs?: number }>;
+  decisions: Record<string, CheckDecision>;
+  requestId?: string;
+  delayMs?: number;
+  budgetExceeded?: boolean;
+  overloaded?: boolean;
+  deniedBy?: string[];
+  annotatedBy?: string[];
+  firstDenied?: string;
+};
+
+export type DenialResponse = {
+  policyId: string;
+  contentType: string;
+  body: string;
 };
 
+export type CheckRequestSource =
+  | Iterable<CheckRequest>
+  | (() => CheckRequest | null | undefined);
+
 export interface Fluxgate {
-  check(req: CheckRequest): CheckResult;
+  check(req: CheckRequest, options?: CheckOptions): CheckResult;
+  peek(req: CheckRequest): CheckResult;
+  checkN(req: CheckRequest, n: number): CheckResult;
   checkBatch(reqs: CheckRequest[]): CheckResult[];
+  checkStream(source: CheckRequestSource, onResult: (result: CheckResult) => void): number;
+  renderDenial(result: CheckResult): DenialResponse | undefined;
+  release(req: CheckRequest): number;
+  releaseKey(key: string): boolean;
+  refund(req: CheckRequest): number;
+  drainDecisionLog(): string;
+  bannedKeys(): BannedKey[];
   rotate(): void;
+  setOverrides(overrides: Record<string, LimitOverride[]>): void;
+  setPressure(factor: number): void;
   reload(cfg: FluxgateInit): void;
   snapshot(): Uint8Array;
   restore(bytes: Uint8Array): void;
+  dispose(): void;
+  stateBytes(): number;
   metrics(): Record<string, number>;
   version(): string;
 }